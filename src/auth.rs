@@ -0,0 +1,192 @@
+use crate::config::Config;
+use crate::vault::send_request;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use log::{error, info};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+#[cfg(test)]
+#[path = "./auth_tests.rs"]
+mod auth_tests;
+
+/// Method used to obtain the Vault token used for every secret read
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VaultAuthMethod {
+    /// Pre-minted token read from `vault_token_path`
+    #[default]
+    Token,
+    /// AppRole login with a role_id and an optional secret_id
+    #[value(name = "approle")]
+    AppRole,
+    /// Kubernetes login with the pod's service-account JWT
+    Kubernetes,
+    /// JWT/OIDC login with a JWT read from a file or the environment
+    Jwt,
+    /// TLS certificate login with the `vault_client_cert` mTLS identity
+    Cert,
+}
+
+impl VaultAuthMethod {
+    /// Mount path of the auth backend when `vault_auth_mount` is not set
+    pub fn default_mount(&self) -> &'static str {
+        match self {
+            VaultAuthMethod::Token => "token",
+            VaultAuthMethod::AppRole => "approle",
+            VaultAuthMethod::Kubernetes => "kubernetes",
+            VaultAuthMethod::Jwt => "jwt",
+            VaultAuthMethod::Cert => "cert",
+        }
+    }
+}
+
+impl fmt::Display for VaultAuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VaultAuthMethod::Token => write!(f, "token"),
+            VaultAuthMethod::AppRole => write!(f, "approle"),
+            VaultAuthMethod::Kubernetes => write!(f, "kubernetes"),
+            VaultAuthMethod::Jwt => write!(f, "jwt"),
+            VaultAuthMethod::Cert => write!(f, "cert"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Default, Clone)]
+pub struct VaultAuth {
+    pub client_token: String,
+    #[serde(default)]
+    pub lease_duration: u64,
+    #[serde(default)]
+    pub renewable: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VaultLoginResponse {
+    pub auth: VaultAuth,
+}
+
+pub async fn login(config: &Config, vault_client: &Client) -> Result<VaultAuth> {
+    match config.vault_auth_method {
+        VaultAuthMethod::Token => Ok(VaultAuth {
+            client_token: parse_token(config)?,
+            ..Default::default()
+        }),
+        VaultAuthMethod::AppRole => {
+            let role_id = read_credential(&config.vault_role_id, &config.vault_role_id_path)
+                .context("Failed to read AppRole role_id")?
+                .ok_or_else(|| anyhow!("vault_role_id or vault_role_id_path must be set"))?;
+            let secret_id = read_credential(&config.vault_secret_id, &config.vault_secret_id_path)
+                .context("Failed to read AppRole secret_id")?;
+            let body = match secret_id {
+                Some(secret_id) => json!({ "role_id": role_id, "secret_id": secret_id }),
+                None => json!({ "role_id": role_id }),
+            };
+            post_login(config, vault_client, body).await
+        }
+        VaultAuthMethod::Kubernetes => {
+            let role = config.vault_auth_role.as_ref().ok_or_else(|| {
+                anyhow!("vault_auth_role must be set with the kubernetes auth method")
+            })?;
+            let jwt = read_file(&config.vault_kubernetes_token_path)
+                .context("Failed to read Kubernetes service-account token")?;
+            post_login(config, vault_client, json!({ "role": role, "jwt": jwt })).await
+        }
+        VaultAuthMethod::Jwt => {
+            let jwt = read_credential(&config.vault_jwt, &config.vault_jwt_path)
+                .context("Failed to read JWT")?
+                .ok_or_else(|| anyhow!("vault_jwt or vault_jwt_path must be set"))?;
+            // Without a role, Vault falls back to the backend's default_role
+            let body = match &config.vault_auth_role {
+                Some(role) => json!({ "role": role, "jwt": jwt }),
+                None => json!({ "jwt": jwt }),
+            };
+            post_login(config, vault_client, body).await
+        }
+        VaultAuthMethod::Cert => {
+            // The client certificate is presented by the TLS layer of
+            // vault_client, the body only selects the certificate role
+            let body = match &config.vault_auth_role {
+                Some(role) => json!({ "name": role }),
+                None => json!({}),
+            };
+            post_login(config, vault_client, body).await
+        }
+    }
+}
+
+async fn post_login(config: &Config, vault_client: &Client, body: Value) -> Result<VaultAuth> {
+    let mount = config
+        .vault_auth_mount
+        .as_deref()
+        .unwrap_or(config.vault_auth_method.default_mount());
+    let url = Url::parse(&format!("{}/v1/auth/{}/login", &config.vault_addr, mount))?;
+    info!("Logging in to Vault at {}", url);
+
+    let response = send_request(vault_client.post(url).json(&body)).await?;
+    let status = response.status();
+    if !status.is_success() {
+        error!("Vault login failed with status {}", status);
+        return Err(anyhow!("Vault login failed with status {}", status));
+    }
+
+    match response.json::<VaultLoginResponse>().await {
+        Ok(login_response) => Ok(login_response.auth),
+        Err(error) => {
+            error!("Failed to parse Vault login response: {}", error);
+            Err(error).context("Failed to parse Vault login response")
+        }
+    }
+}
+
+/// Returns the credential given inline, or else the trimmed content of the
+/// file it points to.
+fn read_credential(value: &Option<String>, path: &Option<PathBuf>) -> Result<Option<String>> {
+    if let Some(value) = value {
+        return Ok(Some(value.trim().to_string()));
+    }
+    path.as_ref().map(read_file).transpose()
+}
+
+fn read_file(path: &PathBuf) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(value) => Ok(value.trim().to_string()),
+        Err(error) => {
+            error!("Failed to read {}: {}", path.display(), error);
+            Err(error).context(format!("Failed to read {}", path.display()))
+        }
+    }
+}
+
+fn parse_token(config: &Config) -> Result<String> {
+    let vault_token_path = config
+        .vault_token_path
+        .as_ref()
+        .ok_or_else(|| anyhow!("vault_token_path must be set with the token auth method"))?;
+
+    match vault_token_path.canonicalize() {
+        Ok(vault_token_path) => {
+            info!(
+                "Reading vault token from file: {}",
+                vault_token_path.display()
+            );
+        }
+        Err(error) => {
+            error!("Failed to canonicalize vault token file path: {}", error);
+            return Err(error).context("Failed to canonicalize vault token file path");
+        }
+    }
+
+    match fs::read_to_string(vault_token_path) {
+        Ok(token) => Ok(token),
+        Err(error) => {
+            error!("Failed to read vault token: {}", error);
+            Err(error).context("Failed to read vault token from file")
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use super::*;
+use wiremock::matchers::{body_json, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn login_response(client_token: &str) -> Value {
+    json!({
+        "auth": {
+            "client_token": client_token,
+            "lease_duration": 3600,
+            "renewable": true
+        }
+    })
+}
+
+#[tokio::test]
+async fn test_login_approle() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/approle/login"))
+        .and(body_json(
+            json!({ "role_id": "role", "secret_id": "secret" }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(login_response("s.approle")))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_auth_method: VaultAuthMethod::AppRole,
+        vault_role_id: Some("role".to_string()),
+        vault_secret_id: Some("secret\n".to_string()),
+        ..Default::default()
+    };
+    let vault_auth = login(&config, &Client::new()).await.unwrap();
+    assert_eq!(
+        vault_auth,
+        VaultAuth {
+            client_token: "s.approle".to_string(),
+            lease_duration: 3600,
+            renewable: true,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_login_approle_custom_mount_without_secret_id() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/signers/login"))
+        .and(body_json(json!({ "role_id": "role" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(login_response("s.signers")))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_auth_method: VaultAuthMethod::AppRole,
+        vault_auth_mount: Some("signers".to_string()),
+        vault_role_id: Some("role".to_string()),
+        ..Default::default()
+    };
+    let vault_auth = login(&config, &Client::new()).await.unwrap();
+    assert_eq!(vault_auth.client_token, "s.signers");
+}
+
+#[tokio::test]
+async fn test_login_approle_denied() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/approle/login"))
+        .respond_with(ResponseTemplate::new(400))
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_auth_method: VaultAuthMethod::AppRole,
+        vault_role_id: Some("role".to_string()),
+        vault_secret_id: Some("wrong".to_string()),
+        ..Default::default()
+    };
+    assert!(login(&config, &Client::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_login_approle_missing_role_id() {
+    let config = Config {
+        vault_addr: "http://127.0.0.1:1".to_string(),
+        vault_auth_method: VaultAuthMethod::AppRole,
+        ..Default::default()
+    };
+    assert!(login(&config, &Client::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_login_kubernetes() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/kubernetes/login"))
+        .and(body_json(
+            json!({ "role": "web3signer", "jwt": "eyJhbGciOi.k8s.sa" }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(login_response("s.kubernetes")))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let token_path = std::env::temp_dir().join("vault-loader-test-kubernetes-token");
+    fs::write(&token_path, "eyJhbGciOi.k8s.sa\n").unwrap();
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_auth_method: VaultAuthMethod::Kubernetes,
+        vault_auth_role: Some("web3signer".to_string()),
+        vault_kubernetes_token_path: token_path.clone(),
+        ..Default::default()
+    };
+    let vault_auth = login(&config, &Client::new()).await;
+    fs::remove_file(token_path).unwrap();
+    assert_eq!(vault_auth.unwrap().client_token, "s.kubernetes");
+}
+
+#[tokio::test]
+async fn test_login_kubernetes_missing_role() {
+    let config = Config {
+        vault_addr: "http://127.0.0.1:1".to_string(),
+        vault_auth_method: VaultAuthMethod::Kubernetes,
+        ..Default::default()
+    };
+    assert!(login(&config, &Client::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_login_jwt() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/jwt/login"))
+        .and(body_json(
+            json!({ "role": "ci", "jwt": "eyJhbGciOi.ci.job" }),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(login_response("s.jwt")))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_auth_method: VaultAuthMethod::Jwt,
+        vault_auth_role: Some("ci".to_string()),
+        vault_jwt: Some("eyJhbGciOi.ci.job".to_string()),
+        ..Default::default()
+    };
+    let vault_auth = login(&config, &Client::new()).await.unwrap();
+    assert_eq!(vault_auth.client_token, "s.jwt");
+}
+
+#[tokio::test]
+async fn test_login_jwt_default_role_from_file() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/spiffe/login"))
+        .and(body_json(json!({ "jwt": "eyJhbGciOi.svid" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(login_response("s.spiffe")))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let jwt_path = std::env::temp_dir().join("vault-loader-test-jwt");
+    fs::write(&jwt_path, "eyJhbGciOi.svid\n").unwrap();
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_auth_method: VaultAuthMethod::Jwt,
+        vault_auth_mount: Some("spiffe".to_string()),
+        vault_jwt_path: Some(jwt_path.clone()),
+        ..Default::default()
+    };
+    let vault_auth = login(&config, &Client::new()).await;
+    fs::remove_file(jwt_path).unwrap();
+    assert_eq!(vault_auth.unwrap().client_token, "s.spiffe");
+}
+
+#[tokio::test]
+async fn test_login_cert() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/cert/login"))
+        .and(body_json(json!({ "name": "signer" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(login_response("s.cert")))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_auth_method: VaultAuthMethod::Cert,
+        vault_auth_role: Some("signer".to_string()),
+        ..Default::default()
+    };
+    let vault_auth = login(&config, &Client::new()).await.unwrap();
+    assert_eq!(vault_auth.client_token, "s.cert");
+}
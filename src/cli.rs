@@ -1,10 +1,15 @@
+use crate::auth::VaultAuthMethod;
+use crate::eip2335::KdfFunction;
+use crate::keymanager::OutputBackend;
+use crate::keystores::KeystoreVariant;
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 #[skip_serializing_none]
-#[derive(Parser, Debug, Serialize, Deserialize)]
+#[derive(Parser, Debug, Serialize, Deserialize, Default)]
 #[command(author, version, about, long_about = None, arg_required_else_help(true))]
 pub struct Cli {
     /// Sets a custom config file
@@ -32,18 +37,149 @@ pub struct Cli {
     #[arg(long, value_name = "KV_PATH")]
     pub vault_path: Option<String>,
 
+    /// Path of each secret, where {vault_path} is replaced by the KV data path
+    /// of vault_path and {pubkey} by the public key. Defaults to
+    /// {vault_path}/{pubkey}/vkey
+    #[arg(long, value_name = "TEMPLATE")]
+    pub vault_secret_path_template: Option<String>,
+
     /// Vault server URL
     #[arg(long, value_name = "URL")]
     pub vault_addr: Option<String>,
 
+    /// Vault Enterprise namespace used for auth logins and secret reads. This
+    /// can also be specified via the VAULT_NAMESPACE environment variable.
+    #[arg(long, value_name = "NAMESPACE")]
+    pub vault_namespace: Option<String>,
+
+    /// Method used to authenticate to Vault
+    #[arg(long, value_enum, value_name = "METHOD")]
+    pub vault_auth_method: Option<VaultAuthMethod>,
+
+    /// Mount path of the Vault auth backend, defaults to the method name
+    #[arg(long, value_name = "MOUNT")]
+    pub vault_auth_mount: Option<String>,
+
+    /// Role to log in as with the kubernetes, jwt and cert auth methods
+    #[arg(long, value_name = "ROLE")]
+    pub vault_auth_role: Option<String>,
+
     /// Path on the local disk to a file containing the Vault token to use
     #[arg(long, value_name = "PATH")]
     pub vault_token_path: Option<PathBuf>,
 
+    /// Path on the local disk to a file containing the AppRole role_id. This
+    /// can also be given inline via the VAULT_ROLE_ID environment variable.
+    #[arg(long, value_name = "PATH")]
+    pub vault_role_id_path: Option<PathBuf>,
+
+    /// Path on the local disk to a file containing the AppRole secret_id. This
+    /// can also be given inline via the VAULT_SECRET_ID environment variable.
+    #[arg(long, value_name = "PATH")]
+    pub vault_secret_id_path: Option<PathBuf>,
+
+    /// Path on the local disk to a file containing the JWT used with the jwt
+    /// auth method. This can also be given inline via the VAULT_JWT
+    /// environment variable.
+    #[arg(long, value_name = "PATH")]
+    pub vault_jwt_path: Option<PathBuf>,
+
+    /// Path on the local disk to the projected Kubernetes service-account
+    /// token, defaults to /var/run/secrets/kubernetes.io/serviceaccount/token
+    #[arg(long, value_name = "PATH")]
+    pub vault_kubernetes_token_path: Option<PathBuf>,
+
     /// Path on the local disk to a json file containing a list of public keys to load
     #[arg(long, value_name = "PATH")]
     pub vault_pubkeys_json_glob: Option<String>,
 
+    /// Discover the public keys to load by listing vault_path, restricted to
+    /// the keys of vault_pubkeys_json_glob when it is also set
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub vault_pubkeys_discovery: Option<bool>,
+
+    /// Path on the local disk to a json file mapping public keys to the KV v2
+    /// secret version to load, in the format of --vault-versions-report-path
+    #[arg(long, value_name = "PATH")]
+    pub vault_versions_json_path: Option<PathBuf>,
+
+    /// Path on the local disk to a json file to write the KV v2 secret
+    /// version and creation time each key was loaded at
+    #[arg(long, value_name = "PATH")]
+    pub vault_versions_report_path: Option<PathBuf>,
+
+    /// Name of the Vault Transit key that encrypted the keystore passwords.
+    /// Passwords stored as transit ciphertext are decrypted with it.
+    #[arg(long, value_name = "KEY")]
+    pub vault_transit_key: Option<String>,
+
+    /// Mount path of the Vault Transit engine, defaults to transit
+    #[arg(long, value_name = "MOUNT")]
+    pub vault_transit_mount: Option<String>,
+
+    /// Also decrypt raw_unencrypted_key when it is stored as transit ciphertext
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub vault_transit_raw_unencrypted_key: Option<bool>,
+
+    /// Maximum number of ciphertexts decrypted per Vault Transit request
+    #[arg(long, value_name = "SIZE")]
+    pub vault_transit_batch_size: Option<usize>,
+
+    /// Check that each private key belongs to the public key it is stored
+    /// under before writing it, enabled by default
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub verify_pubkeys: Option<bool>,
+
+    /// Also decrypt keystores to derive their public key instead of trusting
+    /// their pubkey field
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub verify_pubkeys_decrypt: Option<bool>,
+
+    /// Comma-separated keystore fields to pick from, in order of preference
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "VARIANTS")]
+    pub keystore_preference: Option<Vec<KeystoreVariant>>,
+
+    /// Path to a JSON report of the keystore written for each public key
+    #[arg(long, value_name = "PATH")]
+    pub keystore_report_path: Option<PathBuf>,
+
+    /// Decrypt keystores with their Vault password and encrypt them again with
+    /// a local password before writing them
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub reencrypt_keystores: Option<bool>,
+
+    /// Path to the local password of re-encrypted keystores, a password is
+    /// generated for each key when unset
+    #[arg(long, value_name = "PATH")]
+    pub reencrypt_password_path: Option<PathBuf>,
+
+    /// Wrap raw private keys into keystores with a generated password, so that
+    /// no cleartext private key is written
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub encrypt_raw_keys: Option<bool>,
+
+    /// Key derivation function of the keystores encrypted by vault-loader
+    #[arg(long, value_enum, value_name = "KDF")]
+    pub keystore_kdf: Option<KdfFunction>,
+
+    /// PBKDF2 iteration count of the keystores encrypted by vault-loader
+    #[arg(long, value_name = "COUNT")]
+    pub keystore_pbkdf2_c: Option<u32>,
+
+    /// scrypt cost, a power of two up to 1048576, of the keystores encrypted
+    /// by vault-loader
+    #[arg(long, value_name = "N")]
+    pub keystore_scrypt_n: Option<u32>,
+
+    /// Check that the password decrypts each keystore before writing it
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub verify_keystore_passwords: Option<bool>,
+
+    /// Maximum number of keystore KDFs run concurrently, defaults to the
+    /// number of CPUs
+    #[arg(long, value_name = "COUNT")]
+    pub max_concurrent_kdf: Option<usize>,
+
     /// Maximum number of concurrent requests to Vault
     #[arg(long, value_name = "PATH")]
     pub vault_max_concurrent_requests: Option<usize>,
@@ -52,6 +188,110 @@ pub struct Cli {
     #[arg(long, value_name = "PATH")]
     pub web3signer_key_store_path: Option<PathBuf>,
 
+    /// Where keys are delivered, web3signer key store files by default
+    #[arg(long, value_enum, value_name = "BACKEND")]
+    pub output_backend: Option<OutputBackend>,
+
+    /// URL of the Keymanager API of the validator client to import keys into
+    #[arg(long, value_name = "URL")]
+    pub keymanager_url: Option<String>,
+
+    /// Path to the bearer token of the Keymanager API
+    #[arg(long, value_name = "PATH")]
+    pub keymanager_token_path: Option<PathBuf>,
+
+    /// Maximum number of keystores imported per Keymanager API request
+    #[arg(long, value_name = "SIZE")]
+    pub keymanager_batch_size: Option<usize>,
+
+    /// Path to an EIP-3076 slashing protection interchange file uploaded with
+    /// the imported keystores
+    #[arg(long, value_name = "PATH")]
+    pub keymanager_slashing_protection_path: Option<PathBuf>,
+
+    /// Comma-separated Keymanager API URLs of the validator clients to
+    /// register the loaded keys with as remote keys
+    #[arg(long, value_delimiter = ',', value_name = "URLS")]
+    pub remotekeys_urls: Option<Vec<String>>,
+
+    /// Comma-separated paths to the Keymanager API bearer tokens, one for each
+    /// of the remotekeys URLs
+    #[arg(long, value_delimiter = ',', value_name = "PATHS")]
+    pub remotekeys_token_paths: Option<Vec<PathBuf>>,
+
+    /// URL of web3signer as reached by the validator clients, web3signer_url
+    /// by default
+    #[arg(long, value_name = "URL")]
+    pub remotekeys_signer_url: Option<String>,
+
+    /// Remove the remote keys signed by web3signer that are no longer loaded
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub remotekeys_prune: Option<bool>,
+
+    /// URL of the web3signer API, to reload its keys once they are written
+    #[arg(long, value_name = "URL")]
+    pub web3signer_url: Option<String>,
+
+    /// Seconds to wait for web3signer to load the written keys
+    #[arg(long, value_name = "SECONDS")]
+    pub web3signer_reload_timeout: Option<u64>,
+
+    /// Octal mode of the files written to the web3signer key store
+    #[arg(long, value_name = "MODE")]
+    pub web3signer_file_mode: Option<String>,
+
+    /// Octal mode to set on the web3signer key store directory
+    #[arg(long, value_name = "MODE")]
+    pub web3signer_dir_mode: Option<String>,
+
+    /// User ID owning the files written to the web3signer key store
+    #[arg(long, value_name = "UID")]
+    pub web3signer_uid: Option<u32>,
+
+    /// Group ID owning the files written to the web3signer key store
+    #[arg(long, value_name = "GID")]
+    pub web3signer_gid: Option<u32>,
+
+    /// Write keys even if the web3signer key store is group or world-writable
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub web3signer_allow_insecure_key_store: Option<bool>,
+
+    /// Print what would be added, changed or removed in the web3signer key
+    /// store without writing anything, reports and the metrics textfile
+    /// included, and fail when anything would change
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub dry_run: Option<bool>,
+
+    /// Keep running and load keys again periodically and on SIGHUP
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub daemon: Option<bool>,
+
+    /// Seconds between two loads in daemon mode, 0 to only load on SIGHUP
+    #[arg(long, value_name = "SECONDS")]
+    pub daemon_interval: Option<u64>,
+
+    /// Remove the files of keys that are no longer requested from the
+    /// web3signer key store
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub prune: Option<bool>,
+
+    /// Only list the keys that would be pruned
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub prune_dry_run: Option<bool>,
+
+    /// Maximum number of keys pruned at once, pruning is refused above it
+    #[arg(long, value_name = "COUNT")]
+    pub prune_max_keys: Option<usize>,
+
+    /// Address to serve Prometheus metrics on at /metrics in daemon mode
+    #[arg(long, value_name = "ADDRESS")]
+    pub metrics_listen_address: Option<SocketAddr>,
+
+    /// Path to a node-exporter textfile the metrics are written to at the end
+    /// of a run
+    #[arg(long, value_name = "PATH")]
+    pub metrics_textfile_path: Option<PathBuf>,
+
     /// Maximum number of concurrent requests to Vault
     #[arg(long, value_name = "FD")]
     pub max_open_file_descriptors: Option<usize>,
@@ -1,30 +1,201 @@
+use crate::auth::VaultAuthMethod;
 use crate::cli::Cli;
+use crate::eip2335::{KdfFunction, MAX_SCRYPT_N};
+use crate::keymanager::OutputBackend;
+use crate::keystores::{KeystoreVariant, VaultKeyFields};
 use anyhow::{anyhow, Result};
 use figment::{
     providers::{Env, Format, Serialized, Yaml},
     Figment,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 #[cfg(test)]
 #[path = "./config_tests.rs"]
 mod config_tests;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default, Clone)]
 pub struct Config {
     pub vault_cacert: Option<PathBuf>,
     pub vault_client_cert: Option<PathBuf>,
     pub vault_client_key: Option<PathBuf>,
     pub vault_path: String,
     pub vault_addr: String,
-    pub vault_token_path: PathBuf,
-    pub vault_pubkeys_json_glob: String,
+    pub vault_namespace: Option<String>,
+    #[serde(default = "default_vault_secret_path_template")]
+    pub vault_secret_path_template: String,
+    #[serde(default)]
+    pub vault_secret_fields: VaultKeyFields,
+    #[serde(default)]
+    pub vault_auth_method: VaultAuthMethod,
+    pub vault_auth_mount: Option<String>,
+    pub vault_auth_role: Option<String>,
+    pub vault_token_path: Option<PathBuf>,
+    pub vault_role_id: Option<String>,
+    pub vault_role_id_path: Option<PathBuf>,
+    pub vault_secret_id: Option<String>,
+    pub vault_secret_id_path: Option<PathBuf>,
+    pub vault_jwt: Option<String>,
+    pub vault_jwt_path: Option<PathBuf>,
+    #[serde(default = "default_vault_kubernetes_token_path")]
+    pub vault_kubernetes_token_path: PathBuf,
+    pub vault_pubkeys_json_glob: Option<String>,
+    #[serde(default)]
+    pub vault_pubkeys_discovery: bool,
+    pub vault_versions_json_path: Option<PathBuf>,
+    pub vault_versions_report_path: Option<PathBuf>,
+    pub vault_transit_key: Option<String>,
+    #[serde(default = "default_vault_transit_mount")]
+    pub vault_transit_mount: String,
+    #[serde(default)]
+    pub vault_transit_raw_unencrypted_key: bool,
+    #[serde(default = "default_vault_transit_batch_size")]
+    pub vault_transit_batch_size: usize,
+    #[serde(default = "default_verify_pubkeys")]
+    pub verify_pubkeys: bool,
+    #[serde(default)]
+    pub verify_pubkeys_decrypt: bool,
+    #[serde(default = "KeystoreVariant::default_preference")]
+    pub keystore_preference: Vec<KeystoreVariant>,
+    pub keystore_report_path: Option<PathBuf>,
+    #[serde(default)]
+    pub reencrypt_keystores: bool,
+    pub reencrypt_password_path: Option<PathBuf>,
+    #[serde(default)]
+    pub encrypt_raw_keys: bool,
+    #[serde(default)]
+    pub keystore_kdf: KdfFunction,
+    #[serde(default = "default_keystore_pbkdf2_c")]
+    pub keystore_pbkdf2_c: u32,
+    #[serde(default = "default_keystore_scrypt_n")]
+    pub keystore_scrypt_n: u32,
+    #[serde(default)]
+    pub verify_keystore_passwords: bool,
+    #[serde(default = "default_max_concurrent_kdf")]
+    pub max_concurrent_kdf: usize,
     #[serde(default = "default_vault_max_concurrent_requests")]
     pub vault_max_concurrent_requests: usize,
     #[serde(default = "default_max_open_file_descriptors")]
     pub max_open_file_descriptors: usize,
+    #[serde(default)]
     pub web3signer_key_store_path: PathBuf,
+    #[serde(default)]
+    pub output_backend: OutputBackend,
+    pub keymanager_url: Option<String>,
+    pub keymanager_token_path: Option<PathBuf>,
+    #[serde(default = "default_keymanager_batch_size")]
+    pub keymanager_batch_size: usize,
+    pub keymanager_slashing_protection_path: Option<PathBuf>,
+    #[serde(default)]
+    pub remotekeys_urls: Vec<String>,
+    #[serde(default)]
+    pub remotekeys_token_paths: Vec<PathBuf>,
+    pub remotekeys_signer_url: Option<String>,
+    #[serde(default)]
+    pub remotekeys_prune: bool,
+    pub web3signer_url: Option<String>,
+    #[serde(default = "default_web3signer_reload_timeout")]
+    pub web3signer_reload_timeout: u64,
+    #[serde(
+        default = "default_web3signer_file_mode",
+        deserialize_with = "deserialize_mode"
+    )]
+    pub web3signer_file_mode: u32,
+    #[serde(default, deserialize_with = "deserialize_optional_mode")]
+    pub web3signer_dir_mode: Option<u32>,
+    pub web3signer_uid: Option<u32>,
+    pub web3signer_gid: Option<u32>,
+    #[serde(default)]
+    pub web3signer_allow_insecure_key_store: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub daemon: bool,
+    #[serde(default = "default_daemon_interval")]
+    pub daemon_interval: u64,
+    #[serde(default)]
+    pub prune: bool,
+    #[serde(default)]
+    pub prune_dry_run: bool,
+    #[serde(default = "default_prune_max_keys")]
+    pub prune_max_keys: usize,
+    pub metrics_listen_address: Option<SocketAddr>,
+    pub metrics_textfile_path: Option<PathBuf>,
+}
+
+fn default_daemon_interval() -> u64 {
+    300
+}
+
+fn default_prune_max_keys() -> usize {
+    10
+}
+
+fn default_keymanager_batch_size() -> usize {
+    20
+}
+
+fn default_web3signer_reload_timeout() -> u64 {
+    60
+}
+
+fn default_web3signer_file_mode() -> u32 {
+    0o600
+}
+
+/// Parses a file mode written in octal, such as "0600"
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(anyhow!("{} is not an octal file mode", mode)),
+    }
+}
+
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+    parse_mode(&mode).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_mode<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|mode| parse_mode(&mode).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn default_vault_secret_path_template() -> String {
+    "{vault_path}/{pubkey}/vkey".to_string()
+}
+
+fn default_vault_kubernetes_token_path() -> PathBuf {
+    PathBuf::from("/var/run/secrets/kubernetes.io/serviceaccount/token")
+}
+
+fn default_vault_transit_mount() -> String {
+    "transit".to_string()
+}
+
+fn default_vault_transit_batch_size() -> usize {
+    100
+}
+
+fn default_verify_pubkeys() -> bool {
+    true
+}
+
+fn default_keystore_pbkdf2_c() -> u32 {
+    262144
+}
+
+fn default_keystore_scrypt_n() -> u32 {
+    262144
+}
+
+fn default_max_concurrent_kdf() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
 }
 
 fn default_vault_max_concurrent_requests() -> usize {
@@ -52,6 +223,118 @@ impl Config {
         if has_vault_cacert != has_vault_client_cert || has_vault_cacert != has_vault_client_key {
             return Err(anyhow!("vault_cacert, vault_client_cert, and vault_client_key must be set together or not at all"));
         }
+        if let Ok(template) = config.extract_inner::<String>("vault_secret_path_template") {
+            if !template.contains("{pubkey}") {
+                return Err(anyhow!(
+                    "vault_secret_path_template must contain the {{pubkey}} placeholder"
+                ));
+            }
+        }
+        let has_vault_pubkeys_json_glob = config
+            .extract_inner::<String>("vault_pubkeys_json_glob")
+            .is_ok();
+        let vault_pubkeys_discovery = config
+            .extract_inner::<bool>("vault_pubkeys_discovery")
+            .unwrap_or_default();
+        if !has_vault_pubkeys_json_glob && !vault_pubkeys_discovery {
+            return Err(anyhow!(
+                "vault_pubkeys_json_glob must be set unless vault_pubkeys_discovery is enabled"
+            ));
+        }
+        let vault_auth_method = config
+            .extract_inner::<VaultAuthMethod>("vault_auth_method")
+            .unwrap_or_default();
+        if vault_auth_method == VaultAuthMethod::Cert && !has_vault_client_cert {
+            return Err(anyhow!(
+                "vault_cacert, vault_client_cert, and vault_client_key must be set with the cert auth method"
+            ));
+        }
+        if let Ok(keystore_preference) =
+            config.extract_inner::<Vec<KeystoreVariant>>("keystore_preference")
+        {
+            if keystore_preference.is_empty() {
+                return Err(anyhow!("keystore_preference must not be empty"));
+            }
+        }
+        if let Ok(keystore_scrypt_n) = config.extract_inner::<u32>("keystore_scrypt_n") {
+            if !keystore_scrypt_n.is_power_of_two()
+                || !(2..=MAX_SCRYPT_N).contains(&keystore_scrypt_n)
+            {
+                return Err(anyhow!(
+                    "keystore_scrypt_n must be a power of two of at most {}",
+                    MAX_SCRYPT_N
+                ));
+            }
+        }
+        if matches!(config.extract_inner::<u32>("keystore_pbkdf2_c"), Ok(0)) {
+            return Err(anyhow!("keystore_pbkdf2_c must be at least 1"));
+        }
+        let daemon = config.extract_inner::<bool>("daemon").unwrap_or_default();
+        if daemon && config.extract_inner::<bool>("dry_run").unwrap_or_default() {
+            return Err(anyhow!("dry_run cannot be used in daemon mode"));
+        }
+        if !daemon
+            && config
+                .extract_inner::<SocketAddr>("metrics_listen_address")
+                .is_ok()
+        {
+            return Err(anyhow!(
+                "metrics_listen_address only applies to daemon mode, use metrics_textfile_path"
+            ));
+        }
+        let output_backend = config
+            .extract_inner::<OutputBackend>("output_backend")
+            .unwrap_or_default();
+        if output_backend == OutputBackend::Files
+            && config
+                .extract_inner::<PathBuf>("web3signer_key_store_path")
+                .is_err()
+        {
+            return Err(anyhow!(
+                "web3signer_key_store_path must be set with the files backend"
+            ));
+        }
+        if output_backend == OutputBackend::Keymanager {
+            if config.extract_inner::<String>("keymanager_url").is_err()
+                || config
+                    .extract_inner::<PathBuf>("keymanager_token_path")
+                    .is_err()
+            {
+                return Err(anyhow!(
+                    "keymanager_url and keymanager_token_path must be set with the keymanager backend"
+                ));
+            }
+            for option in ["dry_run", "prune", "prune_dry_run", "remotekeys_prune"] {
+                if config.extract_inner::<bool>(option).unwrap_or_default() {
+                    return Err(anyhow!("{} only applies to the files backend", option));
+                }
+            }
+        }
+        let remotekeys_urls = config
+            .extract_inner::<Vec<String>>("remotekeys_urls")
+            .unwrap_or_default();
+        if !remotekeys_urls.is_empty() {
+            if output_backend != OutputBackend::Files {
+                return Err(anyhow!("remotekeys_urls only applies to the files backend"));
+            }
+            let remotekeys_token_paths = config
+                .extract_inner::<Vec<PathBuf>>("remotekeys_token_paths")
+                .unwrap_or_default();
+            if remotekeys_token_paths.len() != remotekeys_urls.len() {
+                return Err(anyhow!(
+                    "remotekeys_token_paths must hold one token path for each of remotekeys_urls"
+                ));
+            }
+            if config
+                .extract_inner::<String>("remotekeys_signer_url")
+                .is_err()
+                && config.extract_inner::<String>("web3signer_url").is_err()
+            {
+                return Err(anyhow!(
+                    "remotekeys_signer_url or web3signer_url must be set with remotekeys_urls"
+                ));
+            }
+        }
         Ok(config.extract()?)
     }
 }
@@ -1,4 +1,17 @@
 use super::*;
+use crate::eip2335::KdfFunction;
+use crate::keystores::KeystoreVariant;
+
+fn base_cli() -> Cli {
+    Cli {
+        vault_path: Some("ethereum/keys".to_string()),
+        vault_addr: Some("https://vault.domain.name".to_string()),
+        vault_token_path: Some(PathBuf::from("vault_loader/token")),
+        vault_pubkeys_json_glob: Some("/vault_loader/pubkeys.json".to_string()),
+        web3signer_key_store_path: Some(PathBuf::from("/web3signer")),
+        ..Default::default()
+    }
+}
 
 #[test]
 fn test_config_ok() {
@@ -14,10 +27,13 @@ fn test_config_ok() {
         vault_max_concurrent_requests: None,
         web3signer_key_store_path: Some(PathBuf::from("/web3signer")),
         max_open_file_descriptors: Some(1024),
+        ..Default::default()
     };
     let config = Config::new(&args);
     assert!(config.is_ok());
-    assert_eq!(config.unwrap().vault_max_concurrent_requests, 20);
+    let config = config.unwrap();
+    assert_eq!(config.vault_max_concurrent_requests, 20);
+    assert_eq!(config.vault_auth_method, VaultAuthMethod::Token);
 }
 
 #[test]
@@ -34,7 +50,306 @@ fn test_config_err() {
         vault_max_concurrent_requests: None,
         web3signer_key_store_path: Some(PathBuf::from("/web3signer")),
         max_open_file_descriptors: None,
+        ..Default::default()
     };
     let config = Config::new(&args);
     assert!(config.is_err());
 }
+
+#[test]
+fn test_config_approle() {
+    let args = Cli {
+        vault_auth_method: Some(VaultAuthMethod::AppRole),
+        vault_role_id_path: Some(PathBuf::from("/vault_loader/role_id")),
+        vault_secret_id_path: Some(PathBuf::from("/vault_loader/secret_id")),
+        vault_token_path: None,
+        ..base_cli()
+    };
+    let config = Config::new(&args);
+    assert!(config.is_ok());
+    let config = config.unwrap();
+    assert_eq!(config.vault_auth_method, VaultAuthMethod::AppRole);
+    assert_eq!(config.vault_token_path, None);
+    assert_eq!(
+        config.vault_role_id_path,
+        Some(PathBuf::from("/vault_loader/role_id"))
+    );
+}
+
+#[test]
+fn test_config_kubernetes_default_token_path() {
+    let args = Cli {
+        vault_auth_method: Some(VaultAuthMethod::Kubernetes),
+        vault_auth_role: Some("web3signer".to_string()),
+        vault_token_path: None,
+        ..base_cli()
+    };
+    let config = Config::new(&args).unwrap();
+    assert_eq!(config.vault_auth_method, VaultAuthMethod::Kubernetes);
+    assert_eq!(
+        config.vault_kubernetes_token_path,
+        PathBuf::from("/var/run/secrets/kubernetes.io/serviceaccount/token")
+    );
+}
+
+#[test]
+fn test_config_cert_requires_client_identity() {
+    let mut args = Cli {
+        vault_auth_method: Some(VaultAuthMethod::Cert),
+        vault_token_path: None,
+        ..base_cli()
+    };
+    assert!(Config::new(&args).is_err());
+
+    args.vault_cacert = Some(PathBuf::from("/vault_loader/ca.pem"));
+    args.vault_client_cert = Some(PathBuf::from("/vault_loader/client.pem"));
+    args.vault_client_key = Some(PathBuf::from("/vault_loader/client.key"));
+    let config = Config::new(&args).unwrap();
+    assert_eq!(config.vault_auth_method, VaultAuthMethod::Cert);
+    assert_eq!(config.vault_token_path, None);
+}
+
+#[test]
+fn test_config_namespace() {
+    let args = Cli {
+        vault_namespace: Some("admin/goerli".to_string()),
+        ..base_cli()
+    };
+    let config = Config::new(&args).unwrap();
+    assert_eq!(config.vault_namespace, Some("admin/goerli".to_string()));
+
+    let args = Cli {
+        vault_namespace: None,
+        ..args
+    };
+    let config = Config::new(&args).unwrap();
+    assert_eq!(config.vault_namespace, None);
+}
+
+#[test]
+fn test_config_pubkeys_discovery() {
+    let args = Cli {
+        vault_pubkeys_json_glob: None,
+        ..base_cli()
+    };
+    assert!(Config::new(&args).is_err());
+
+    let args = Cli {
+        vault_pubkeys_discovery: Some(true),
+        ..args
+    };
+    let config = Config::new(&args).unwrap();
+    assert!(config.vault_pubkeys_discovery);
+    assert_eq!(config.vault_pubkeys_json_glob, None);
+}
+
+#[test]
+fn test_config_secret_layout() {
+    let config_path = std::env::temp_dir().join("vault-loader-test-secret-layout.yaml");
+    std::fs::write(
+        &config_path,
+        r#"
+vault_secret_path_template: "{vault_path}/{pubkey}"
+vault_secret_fields:
+  vkey: keystore
+  password: passphrase
+"#,
+    )
+    .unwrap();
+    let args = Cli {
+        config: Some(config_path.clone()),
+        ..base_cli()
+    };
+    let config = Config::new(&args);
+    std::fs::remove_file(config_path).unwrap();
+    let config = config.unwrap();
+    assert_eq!(config.vault_secret_path_template, "{vault_path}/{pubkey}");
+    assert_eq!(
+        config.vault_secret_fields,
+        VaultKeyFields {
+            vkey: "keystore".to_string(),
+            password: "passphrase".to_string(),
+            ..Default::default()
+        }
+    );
+
+    let args = Cli {
+        config: None,
+        vault_secret_path_template: Some("{vault_path}/vkey".to_string()),
+        ..args
+    };
+    assert!(Config::new(&args).is_err());
+}
+
+#[test]
+fn test_config_keystore_preference() {
+    let args = base_cli();
+    let config = Config::new(&args).unwrap();
+    assert_eq!(
+        config.keystore_preference,
+        KeystoreVariant::default_preference()
+    );
+
+    let args = Cli {
+        keystore_preference: Some(vec![KeystoreVariant::Scrypt, KeystoreVariant::Vkey]),
+        ..args
+    };
+    let config = Config::new(&args).unwrap();
+    assert_eq!(
+        config.keystore_preference,
+        vec![KeystoreVariant::Scrypt, KeystoreVariant::Vkey]
+    );
+
+    let args = Cli {
+        keystore_preference: Some(vec![]),
+        ..args
+    };
+    assert!(Config::new(&args).is_err());
+}
+
+#[test]
+fn test_config_keystore_kdf() {
+    let args = base_cli();
+    let config = Config::new(&args).unwrap();
+    assert_eq!(config.keystore_kdf, KdfFunction::Pbkdf2);
+    assert_eq!(config.keystore_pbkdf2_c, 262144);
+
+    let args = Cli {
+        keystore_kdf: Some(KdfFunction::Scrypt),
+        keystore_scrypt_n: Some(1000),
+        ..args
+    };
+    assert!(Config::new(&args).is_err());
+
+    let args = Cli {
+        keystore_scrypt_n: Some(1 << 22),
+        ..args
+    };
+    assert!(Config::new(&args).is_err());
+
+    let args = Cli {
+        keystore_scrypt_n: Some(1024),
+        ..args
+    };
+    assert_eq!(Config::new(&args).unwrap().keystore_scrypt_n, 1024);
+}
+
+#[test]
+fn test_config_file_modes() {
+    let args = base_cli();
+    let config = Config::new(&args).unwrap();
+    assert_eq!(config.web3signer_file_mode, 0o600);
+    assert_eq!(config.web3signer_dir_mode, None);
+
+    let args = Cli {
+        web3signer_file_mode: Some("0640".to_string()),
+        web3signer_dir_mode: Some("0o750".to_string()),
+        ..args
+    };
+    let config = Config::new(&args).unwrap();
+    assert_eq!(config.web3signer_file_mode, 0o640);
+    assert_eq!(config.web3signer_dir_mode, Some(0o750));
+
+    let args = Cli {
+        web3signer_file_mode: Some("0800".to_string()),
+        ..args
+    };
+    assert!(Config::new(&args).is_err());
+}
+
+#[test]
+fn test_config_daemon() {
+    let args = Cli {
+        daemon: Some(true),
+        ..base_cli()
+    };
+    let config = Config::new(&args).unwrap();
+    assert!(config.daemon);
+    assert_eq!(config.daemon_interval, 300);
+
+    let args = Cli {
+        dry_run: Some(true),
+        ..args
+    };
+    assert!(Config::new(&args).is_err());
+}
+
+#[test]
+fn test_config_output_backend() {
+    let args = Cli {
+        web3signer_key_store_path: None,
+        ..base_cli()
+    };
+    assert!(Config::new(&args).is_err());
+
+    let args = Cli {
+        output_backend: Some(OutputBackend::Keymanager),
+        ..args
+    };
+    assert!(Config::new(&args).is_err());
+
+    let args = Cli {
+        keymanager_url: Some("https://validator:5062".to_string()),
+        keymanager_token_path: Some(PathBuf::from("/vault_loader/api-token.txt")),
+        ..args
+    };
+    let config = Config::new(&args).unwrap();
+    assert_eq!(config.output_backend, OutputBackend::Keymanager);
+    assert_eq!(config.keymanager_batch_size, 20);
+
+    let args = Cli {
+        prune: Some(true),
+        ..args
+    };
+    assert!(Config::new(&args).is_err());
+}
+
+#[test]
+fn test_config_remotekeys() {
+    let args = Cli {
+        remotekeys_urls: Some(vec![
+            "https://validator-1:5062".to_string(),
+            "https://validator-2:5062".to_string(),
+        ]),
+        remotekeys_token_paths: Some(vec![PathBuf::from("/vault_loader/api-token.txt")]),
+        web3signer_url: Some("http://web3signer:9000".to_string()),
+        ..base_cli()
+    };
+    assert!(Config::new(&args).is_err());
+
+    let args = Cli {
+        remotekeys_token_paths: Some(vec![
+            PathBuf::from("/vault_loader/api-token-1.txt"),
+            PathBuf::from("/vault_loader/api-token-2.txt"),
+        ]),
+        ..args
+    };
+    let config = Config::new(&args).unwrap();
+    assert_eq!(config.remotekeys_urls.len(), 2);
+    assert!(!config.remotekeys_prune);
+
+    let args = Cli {
+        web3signer_url: None,
+        ..args
+    };
+    assert!(Config::new(&args).is_err());
+}
+
+#[test]
+fn test_config_metrics() {
+    let args = Cli {
+        metrics_listen_address: Some("127.0.0.1:9100".parse().unwrap()),
+        ..base_cli()
+    };
+    assert!(Config::new(&args).is_err());
+
+    let args = Cli {
+        daemon: Some(true),
+        ..args
+    };
+    let config = Config::new(&args).unwrap();
+    assert_eq!(
+        config.metrics_listen_address,
+        Some("127.0.0.1:9100".parse().unwrap())
+    );
+}
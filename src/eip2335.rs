@@ -0,0 +1,376 @@
+use aes::cipher::{KeyIvInit, StreamCipher};
+use anyhow::{anyhow, Result};
+use blst::min_pk::SecretKey;
+use clap::ValueEnum;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+use zeroize::Zeroizing;
+
+#[cfg(test)]
+#[path = "./eip2335_tests.rs"]
+mod eip2335_tests;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+/// Largest scrypt `n` accepted, four times the EIP-2335 default, so that a
+/// malformed keystore cannot make its KDF allocate gigabytes
+pub const MAX_SCRYPT_N: u32 = 1 << 20;
+/// Largest scrypt block size `r` accepted
+const MAX_SCRYPT_R: u32 = 8;
+/// Largest scrypt parallelization `p` accepted
+const MAX_SCRYPT_P: u32 = 16;
+
+/// Key derivation function of the keystores encrypted by vault-loader
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KdfFunction {
+    /// PBKDF2 with HMAC-SHA256, cost is the iteration count `c`
+    #[default]
+    Pbkdf2,
+    /// scrypt with r = 8 and p = 1, cost is the power of two `n`
+    Scrypt,
+}
+
+/// EIP-2335 BLS12-381 keystore
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Keystore {
+    pub crypto: KeystoreCrypto,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub pubkey: String,
+    #[serde(default)]
+    pub path: String,
+    pub uuid: String,
+    pub version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct KeystoreCrypto {
+    pub kdf: KeystoreModule<KdfParams>,
+    pub checksum: KeystoreModule<Map<String, Value>>,
+    pub cipher: KeystoreModule<CipherParams>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct KeystoreModule<P> {
+    pub function: String,
+    pub params: P,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum KdfParams {
+    Scrypt {
+        dklen: usize,
+        n: u32,
+        r: u32,
+        p: u32,
+        salt: String,
+    },
+    Pbkdf2 {
+        dklen: usize,
+        c: u32,
+        prf: String,
+        salt: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CipherParams {
+    pub iv: String,
+}
+
+impl KdfParams {
+    /// Checks the KDF parameters against sane bounds, before they size any
+    /// buffer or loop
+    fn validate(&self) -> Result<()> {
+        match self {
+            KdfParams::Scrypt { dklen, n, r, p, .. } => {
+                if *dklen != 32 {
+                    return Err(anyhow!("kdf dklen must be 32 bytes"));
+                }
+                if !n.is_power_of_two() || !(2..=MAX_SCRYPT_N).contains(n) {
+                    return Err(anyhow!(
+                        "scrypt n must be a power of two of at most {}",
+                        MAX_SCRYPT_N
+                    ));
+                }
+                if !(1..=MAX_SCRYPT_R).contains(r) {
+                    return Err(anyhow!("scrypt r must be between 1 and {}", MAX_SCRYPT_R));
+                }
+                if !(1..=MAX_SCRYPT_P).contains(p) {
+                    return Err(anyhow!("scrypt p must be between 1 and {}", MAX_SCRYPT_P));
+                }
+            }
+            KdfParams::Pbkdf2 { dklen, c, .. } => {
+                if *dklen != 32 {
+                    return Err(anyhow!("kdf dklen must be 32 bytes"));
+                }
+                if *c == 0 {
+                    return Err(anyhow!("pbkdf2 c must be positive"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Keystore {
+    /// Encrypts a secret key with `password` under a fresh salt, IV and UUID
+    pub fn encrypt(secret: &[u8], password: &str, kdf: KdfFunction, cost: u32) -> Result<Self> {
+        let pubkey = bls_pubkey(secret)?;
+        let salt = hex::encode(random_bytes(32));
+        let params = match kdf {
+            KdfFunction::Pbkdf2 => KdfParams::Pbkdf2 {
+                dklen: 32,
+                c: cost,
+                prf: "hmac-sha256".to_string(),
+                salt,
+            },
+            KdfFunction::Scrypt => KdfParams::Scrypt {
+                dklen: 32,
+                n: cost,
+                r: 8,
+                p: 1,
+                salt,
+            },
+        };
+        let mut keystore = Keystore {
+            crypto: KeystoreCrypto {
+                kdf: KeystoreModule {
+                    function: kdf.to_string(),
+                    params,
+                    message: String::new(),
+                },
+                checksum: KeystoreModule {
+                    function: "sha256".to_string(),
+                    params: Map::new(),
+                    message: String::new(),
+                },
+                cipher: KeystoreModule {
+                    function: "aes-128-ctr".to_string(),
+                    params: CipherParams {
+                        iv: hex::encode(random_bytes(16)),
+                    },
+                    message: String::new(),
+                },
+            },
+            description: String::new(),
+            pubkey: normalize_pubkey(&pubkey),
+            path: String::new(),
+            uuid: uuid::Uuid::new_v4().to_string(),
+            version: 4,
+        };
+
+        let encryption_key = keystore.derive_key(password)?;
+        let mut cipher_message = secret.to_vec();
+        let iv = hex::decode(&keystore.crypto.cipher.params.iv)?;
+        let mut cipher = Aes128Ctr::new_from_slices(&encryption_key[..16], &iv)
+            .map_err(|error| anyhow!("Invalid aes-128-ctr parameters: {}", error))?;
+        cipher.apply_keystream(&mut cipher_message);
+        keystore.crypto.cipher.message = hex::encode(&cipher_message);
+        keystore.crypto.checksum.message = hex::encode(checksum(&encryption_key, &cipher_message));
+        keystore.validate()?;
+        Ok(keystore)
+    }
+
+    /// KDF of the keystore and its cost parameter, when it is one that
+    /// vault-loader encrypts with
+    pub fn kdf(&self) -> Option<(KdfFunction, u32)> {
+        match (self.crypto.kdf.function.as_str(), &self.crypto.kdf.params) {
+            ("pbkdf2", KdfParams::Pbkdf2 { dklen: 32, c, .. }) => Some((KdfFunction::Pbkdf2, *c)),
+            (
+                "scrypt",
+                KdfParams::Scrypt {
+                    dklen: 32,
+                    n,
+                    r: 8,
+                    p: 1,
+                    ..
+                },
+            ) => Some((KdfFunction::Scrypt, *n)),
+            _ => None,
+        }
+    }
+
+    /// Checks that the keystore is a version 4 keystore using functions and
+    /// parameters that web3signer can decrypt, without running the KDF.
+    pub fn validate(&self) -> Result<()> {
+        if self.version != 4 {
+            return Err(anyhow!("Unsupported keystore version {}", self.version));
+        }
+        let salt = match (self.crypto.kdf.function.as_str(), &self.crypto.kdf.params) {
+            ("scrypt", KdfParams::Scrypt { salt, .. }) => salt,
+            ("pbkdf2", KdfParams::Pbkdf2 { prf, salt, .. }) => {
+                if prf != "hmac-sha256" {
+                    return Err(anyhow!("Unsupported pbkdf2 prf {}", prf));
+                }
+                salt
+            }
+            (function, _) => {
+                return Err(anyhow!(
+                    "Unsupported kdf {} or parameters not matching it",
+                    function
+                ))
+            }
+        };
+        self.crypto.kdf.params.validate()?;
+        decode_hex("kdf salt", salt, None)?;
+        if self.crypto.checksum.function != "sha256" {
+            return Err(anyhow!(
+                "Unsupported checksum {}",
+                self.crypto.checksum.function
+            ));
+        }
+        decode_hex("checksum message", &self.crypto.checksum.message, Some(32))?;
+        if self.crypto.cipher.function != "aes-128-ctr" {
+            return Err(anyhow!(
+                "Unsupported cipher {}",
+                self.crypto.cipher.function
+            ));
+        }
+        decode_hex("cipher iv", &self.crypto.cipher.params.iv, Some(16))?;
+        decode_hex("cipher message", &self.crypto.cipher.message, Some(32))?;
+        Ok(())
+    }
+
+    /// Checks the password against the keystore checksum, which costs one
+    /// run of the KDF.
+    pub fn verify_password(&self, password: &str) -> Result<()> {
+        self.verify_checksum(&self.derive_key(password)?)
+    }
+
+    /// Decrypts the secret key, failing when the password does not match the
+    /// keystore checksum.
+    pub fn decrypt(&self, password: &str) -> Result<Zeroizing<Vec<u8>>> {
+        let decryption_key = self.derive_key(password)?;
+        self.verify_checksum(&decryption_key)?;
+        let mut secret = Zeroizing::new(hex::decode(&self.crypto.cipher.message)?);
+        let iv = hex::decode(&self.crypto.cipher.params.iv)?;
+        let mut cipher = Aes128Ctr::new_from_slices(&decryption_key[..16], &iv)
+            .map_err(|error| anyhow!("Invalid aes-128-ctr parameters: {}", error))?;
+        cipher.apply_keystream(&mut secret);
+        Ok(secret)
+    }
+
+    fn derive_key(&self, password: &str) -> Result<Zeroizing<Vec<u8>>> {
+        self.crypto.kdf.params.validate()?;
+        let password = normalize_password(password);
+        match &self.crypto.kdf.params {
+            KdfParams::Scrypt {
+                dklen,
+                n,
+                r,
+                p,
+                salt,
+            } => {
+                let params = scrypt::Params::new(n.trailing_zeros() as u8, *r, *p, *dklen)
+                    .map_err(|error| anyhow!("Invalid scrypt parameters: {}", error))?;
+                let mut decryption_key = Zeroizing::new(vec![0u8; *dklen]);
+                scrypt::scrypt(&password, &hex::decode(salt)?, &params, &mut decryption_key)
+                    .map_err(|error| anyhow!("Invalid scrypt key length: {}", error))?;
+                Ok(decryption_key)
+            }
+            KdfParams::Pbkdf2 { dklen, c, salt, .. } => {
+                let mut decryption_key = Zeroizing::new(vec![0u8; *dklen]);
+                pbkdf2::pbkdf2_hmac::<Sha256>(
+                    &password,
+                    &hex::decode(salt)?,
+                    *c,
+                    &mut decryption_key,
+                );
+                Ok(decryption_key)
+            }
+        }
+    }
+
+    fn verify_checksum(&self, decryption_key: &[u8]) -> Result<()> {
+        if decryption_key.len() < 32 {
+            return Err(anyhow!("Keystore decryption key must be at least 32 bytes"));
+        }
+        let cipher_message = hex::decode(&self.crypto.cipher.message)?;
+        if hex::encode(checksum(decryption_key, &cipher_message))
+            != self.crypto.checksum.message.to_lowercase()
+        {
+            return Err(anyhow!("Keystore checksum mismatch, the password is wrong"));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for KdfFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KdfFunction::Pbkdf2 => write!(f, "pbkdf2"),
+            KdfFunction::Scrypt => write!(f, "scrypt"),
+        }
+    }
+}
+
+fn checksum(decryption_key: &[u8], cipher_message: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(&decryption_key[16..32]);
+    hasher.update(cipher_message);
+    hasher.finalize().to_vec()
+}
+
+fn random_bytes(length: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; length];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Generates a random keystore password
+pub fn generate_password() -> Zeroizing<String> {
+    Zeroizing::new(hex::encode(Zeroizing::new(random_bytes(32))))
+}
+
+fn decode_hex(name: &str, value: &str, length: Option<usize>) -> Result<Vec<u8>> {
+    let bytes = hex::decode(value).map_err(|error| anyhow!("Invalid {}: {}", name, error))?;
+    match length {
+        Some(length) if bytes.len() != length => Err(anyhow!(
+            "Invalid {}: expected {} bytes, got {}",
+            name,
+            length,
+            bytes.len()
+        )),
+        _ => Ok(bytes),
+    }
+}
+
+/// Normalizes a keystore password as EIP-2335 requires: NFKD, without C0, C1
+/// and Delete control codes.
+fn normalize_password(password: &str) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(
+        password
+            .nfkd()
+            .filter(|c| !matches!(*c as u32, 0x00..=0x1f | 0x7f..=0x9f))
+            .collect::<String>()
+            .into_bytes(),
+    )
+}
+
+/// Derives the 0x-prefixed, compressed BLS12-381 public key of a secret key
+pub fn bls_pubkey(secret: &[u8]) -> Result<String> {
+    let secret_key = SecretKey::from_bytes(secret)
+        .map_err(|error| anyhow!("Invalid BLS secret key: {:?}", error))?;
+    Ok(format!(
+        "0x{}",
+        hex::encode(secret_key.sk_to_pk().compress())
+    ))
+}
+
+/// Lowercases a public key and strips its 0x prefix, for comparisons
+pub fn normalize_pubkey(pubkey: &str) -> String {
+    pubkey
+        .trim()
+        .to_lowercase()
+        .trim_start_matches("0x")
+        .to_string()
+}
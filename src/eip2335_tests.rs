@@ -0,0 +1,201 @@
+use super::*;
+
+const PASSWORD: &str = "𝔱𝔢𝔰𝔱𝔭𝔞𝔰𝔰𝔴𝔬𝔯𝔡🔑";
+const SECRET: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+const PUBKEY: &str = "0x9612d7a727c9d0a22e185a1c768478dfe919cada9266988cb32359c11f2b7b27f4ae4040902382ae2910c15e2b420d07";
+
+const PBKDF2_KEYSTORE: &str = r#"{
+    "crypto": {
+        "kdf": {
+            "function": "pbkdf2",
+            "params": {
+                "dklen": 32,
+                "c": 262144,
+                "prf": "hmac-sha256",
+                "salt": "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+            },
+            "message": ""
+        },
+        "checksum": {
+            "function": "sha256",
+            "params": {},
+            "message": "8a9f5d9912ed7e75ea794bc5a89bca5f193721d30868ade6f73043c6ea6febf1"
+        },
+        "cipher": {
+            "function": "aes-128-ctr",
+            "params": {
+                "iv": "264daa3f303d7259501c93d997d84fe6"
+            },
+            "message": "cee03fde2af33149775b7223e7845e4fb2c8ae1792e5f99fe9ecf474cc8c16ad"
+        }
+    },
+    "description": "This is a test keystore that uses PBKDF2 to secure the secret.",
+    "pubkey": "9612d7a727c9d0a22e185a1c768478dfe919cada9266988cb32359c11f2b7b27f4ae4040902382ae2910c15e2b420d07",
+    "path": "m/12381/60/0/0",
+    "uuid": "64625def-3331-4eea-ab6f-782f3ed16a83",
+    "version": 4
+}"#;
+
+const SCRYPT_KEYSTORE: &str = r#"{
+    "crypto": {
+        "kdf": {
+            "function": "scrypt",
+            "params": {
+                "dklen": 32,
+                "n": 262144,
+                "p": 1,
+                "r": 8,
+                "salt": "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+            },
+            "message": ""
+        },
+        "checksum": {
+            "function": "sha256",
+            "params": {},
+            "message": "d2217fe5f3e9a1e34581ef8a78f7c9928e436d36dacc5e846690a5581e8ea484"
+        },
+        "cipher": {
+            "function": "aes-128-ctr",
+            "params": {
+                "iv": "264daa3f303d7259501c93d997d84fe6"
+            },
+            "message": "06ae90d55fe0a6e9c5c3bc5b170827b2e5cce3929ed3f116c2811e6366dfe20f"
+        }
+    },
+    "description": "This is a test keystore that uses scrypt to secure the secret.",
+    "pubkey": "9612d7a727c9d0a22e185a1c768478dfe919cada9266988cb32359c11f2b7b27f4ae4040902382ae2910c15e2b420d07",
+    "path": "m/12381/60/3141592653/589793238",
+    "uuid": "1d85ae20-35c5-4611-98e8-aa14a633906f",
+    "version": 4
+}"#;
+
+#[test]
+fn test_keystore_decrypt_pbkdf2() {
+    let keystore: Keystore = serde_json::from_str(PBKDF2_KEYSTORE).unwrap();
+    assert!(matches!(
+        keystore.crypto.kdf.params,
+        KdfParams::Pbkdf2 { .. }
+    ));
+    assert_eq!(hex::encode(keystore.decrypt(PASSWORD).unwrap()), SECRET);
+    assert!(keystore.decrypt("wrong password").is_err());
+}
+
+#[test]
+fn test_keystore_decrypt_scrypt() {
+    let keystore: Keystore = serde_json::from_str(SCRYPT_KEYSTORE).unwrap();
+    assert!(matches!(
+        keystore.crypto.kdf.params,
+        KdfParams::Scrypt { .. }
+    ));
+    assert_eq!(hex::encode(keystore.decrypt(PASSWORD).unwrap()), SECRET);
+}
+
+#[test]
+fn test_normalize_password() {
+    assert_eq!(
+        *normalize_password(PASSWORD),
+        "testpassword🔑".as_bytes().to_vec()
+    );
+    assert_eq!(
+        *normalize_password("pass\u{7f}word\n"),
+        b"password".to_vec()
+    );
+}
+
+#[test]
+fn test_bls_pubkey() {
+    assert_eq!(bls_pubkey(&hex::decode(SECRET).unwrap()).unwrap(), PUBKEY);
+    assert!(bls_pubkey(&[0u8; 32]).is_err());
+}
+
+#[test]
+fn test_keystore_validate() {
+    let keystore: Keystore = serde_json::from_str(SCRYPT_KEYSTORE).unwrap();
+    assert!(keystore.validate().is_ok());
+    assert!(keystore.verify_password(PASSWORD).is_ok());
+    assert!(keystore.verify_password("password").is_err());
+
+    let keystore: Keystore = serde_json::from_str(PBKDF2_KEYSTORE).unwrap();
+    assert!(keystore.validate().is_ok());
+
+    let mut invalid = keystore.clone();
+    invalid.version = 3;
+    assert!(invalid.validate().is_err());
+
+    let mut invalid = keystore.clone();
+    invalid.crypto.kdf.function = "scrypt".to_owned();
+    assert!(invalid.validate().is_err());
+
+    let mut invalid = keystore.clone();
+    invalid.crypto.cipher.function = "aes-256-gcm".to_owned();
+    assert!(invalid.validate().is_err());
+
+    let mut invalid = keystore.clone();
+    invalid.crypto.cipher.params.iv = "264daa3f".to_owned();
+    assert!(invalid.validate().is_err());
+
+    let mut invalid = keystore.clone();
+    invalid.crypto.checksum.message = "not hex".to_owned();
+    assert!(invalid.validate().is_err());
+
+    // KDF parameters that would allocate or spin are rejected before use
+    for params in [
+        KdfParams::Pbkdf2 {
+            dklen: 1 << 40,
+            c: 262144,
+            prf: "hmac-sha256".to_owned(),
+            salt: "d4e56740".to_owned(),
+        },
+        KdfParams::Pbkdf2 {
+            dklen: 32,
+            c: 0,
+            prf: "hmac-sha256".to_owned(),
+            salt: "d4e56740".to_owned(),
+        },
+    ] {
+        let mut invalid = keystore.clone();
+        invalid.crypto.kdf.params = params;
+        assert!(invalid.validate().is_err());
+        assert!(invalid.verify_password(PASSWORD).is_err());
+    }
+
+    let keystore: Keystore = serde_json::from_str(SCRYPT_KEYSTORE).unwrap();
+    for (dklen, n, r, p) in [
+        (1 << 40, 262144, 8, 1),
+        (32, 1 << 30, 8, 1),
+        (32, 262144, 1 << 20, 1),
+        (32, 262144, 8, 1 << 20),
+        (32, 262144, 0, 1),
+        (32, 262144, 8, 0),
+    ] {
+        let mut invalid = keystore.clone();
+        invalid.crypto.kdf.params = KdfParams::Scrypt {
+            dklen,
+            n,
+            r,
+            p,
+            salt: "d4e56740".to_owned(),
+        };
+        assert!(invalid.validate().is_err());
+        assert!(invalid.verify_password(PASSWORD).is_err());
+    }
+}
+
+#[test]
+fn test_keystore_encrypt() {
+    let secret = hex::decode(SECRET).unwrap();
+    for (kdf, cost) in [(KdfFunction::Pbkdf2, 16), (KdfFunction::Scrypt, 16)] {
+        let keystore = Keystore::encrypt(&secret, "password", kdf, cost).unwrap();
+        assert_eq!(keystore.crypto.kdf.function, kdf.to_string());
+        assert_eq!(keystore.version, 4);
+        assert_eq!(keystore.pubkey, normalize_pubkey(PUBKEY));
+        assert!(keystore.validate().is_ok());
+        assert_eq!(*keystore.decrypt("password").unwrap(), secret);
+        assert!(keystore.decrypt(PASSWORD).is_err());
+
+        let other = Keystore::encrypt(&secret, "password", kdf, cost).unwrap();
+        assert_ne!(keystore.uuid, other.uuid);
+        assert_ne!(keystore.crypto.cipher.message, other.crypto.cipher.message);
+    }
+    assert!(Keystore::encrypt(&secret, "password", KdfFunction::Scrypt, 15).is_err());
+}
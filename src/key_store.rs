@@ -0,0 +1,331 @@
+use anyhow::{anyhow, Context, Error, Result};
+use log::{info, warn};
+use rand::{distributions::Alphanumeric, Rng};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+use std::path::Path;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use zeroize::Zeroizing;
+
+use crate::config::Config;
+use crate::eip2335::{normalize_pubkey, Keystore};
+use crate::keystores::{VaultKey, Web3signerKeyConfig, Web3signerKeyConfigFormat};
+use crate::metrics::METRICS;
+
+#[cfg(test)]
+#[path = "./key_store_tests.rs"]
+mod key_store_tests;
+
+/// Mode and owner of the files written to the key store
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilePermissions {
+    pub mode: u32,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FilePermissions {
+    pub fn new(config: &Config) -> Self {
+        FilePermissions {
+            mode: config.web3signer_file_mode,
+            uid: config.web3signer_uid,
+            gid: config.web3signer_gid,
+        }
+    }
+}
+
+/// Applies the configured mode and owner to the key store directory, then
+/// refuses a directory that other users could write keys into. A dry run only
+/// checks that the directory exists.
+pub fn prepare_key_store(config: &Config) -> Result<(), Error> {
+    let path = &config.web3signer_key_store_path;
+    if config.dry_run {
+        return std::fs::metadata(path)
+            .map(|_| ())
+            .with_context(|| format!("Failed to read {}", path.display()));
+    }
+    if let Some(mode) = config.web3signer_dir_mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    if config.web3signer_uid.is_some() || config.web3signer_gid.is_some() {
+        chown(path, config.web3signer_uid, config.web3signer_gid)?;
+    }
+    let mode = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .mode();
+    if mode & 0o022 != 0 && !config.web3signer_allow_insecure_key_store {
+        return Err(anyhow!(
+            "{} is group or world-writable (mode {:o}), set web3signer_dir_mode or web3signer_allow_insecure_key_store",
+            path.display(),
+            mode & 0o7777
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a key would be added, changed, left unchanged or removed in the
+/// key store
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyChange {
+    Added,
+    Changed,
+    Unchanged,
+    Removed,
+}
+
+impl fmt::Display for KeyChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyChange::Added => write!(f, "added"),
+            KeyChange::Changed => write!(f, "changed"),
+            KeyChange::Unchanged => write!(f, "unchanged"),
+            KeyChange::Removed => write!(f, "removed"),
+        }
+    }
+}
+
+/// Names and contents of the web3signer files of a key, the YAML config last
+/// so that web3signer never loads a config whose keystore is not fully
+/// written.
+pub fn key_files(vault_key: &VaultKey) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    match vault_key.to_config()? {
+        Web3signerKeyConfigFormat::Web3signerFileRaw(config) => Ok(vec![(
+            config.filename.clone(),
+            config.to_yaml()?.into_bytes(),
+        )]),
+        Web3signerKeyConfigFormat::Web3signerFileKeystore(config) => Ok(vec![
+            (
+                config.keystore_file.clone(),
+                serde_json::to_vec(&config.keystore_file_content)?,
+            ),
+            (
+                config.keystore_password_file.clone(),
+                config.keystore_password_file_content.clone().into_bytes(),
+            ),
+            (config.filename.clone(), config.to_yaml()?.into_bytes()),
+        ]),
+    }
+}
+
+/// Writes the web3signer files of a key
+pub async fn write_vault_key(
+    vault_key: &VaultKey,
+    path: &Path,
+    permissions: &FilePermissions,
+) -> Result<(), Error> {
+    for (file_name, content) in key_files(vault_key)? {
+        write_file(&path.join(file_name), &content, permissions).await?;
+        METRICS.bytes_written.inc_by(content.len() as u64);
+    }
+    Ok(())
+}
+
+/// Keystore and password that the key store holds for a key
+pub struct StoredKeystore {
+    pub keystore: Keystore,
+    pub password: Zeroizing<String>,
+}
+
+/// Reads the keystore and password of a key from the key store, `None` when
+/// either is missing or the keystore cannot be parsed
+pub fn read_stored_keystore(path: &Path, pubkey: &str) -> Result<Option<StoredKeystore>, Error> {
+    let read = |extension: &str| {
+        let file_path = path.join(format!("keystore-{}.{}", pubkey, extension));
+        match std::fs::read_to_string(&file_path) {
+            Ok(content) => Ok(Some(Zeroizing::new(content))),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error).context(format!("Failed to read {}", file_path.display())),
+        }
+    };
+    let (keystore, password) = match (read("json")?, read("password")?) {
+        (Some(keystore), Some(password)) => (keystore, password),
+        _ => return Ok(None),
+    };
+    Ok(serde_json::from_str(&keystore)
+        .ok()
+        .map(|keystore| StoredKeystore { keystore, password }))
+}
+
+/// Compares the web3signer files of a key, and the mode and owner they are
+/// written with, with the ones in the key store
+pub async fn diff_vault_key(
+    vault_key: &VaultKey,
+    path: &Path,
+    permissions: &FilePermissions,
+) -> Result<KeyChange, Error> {
+    let mut existing_files = 0;
+    let mut identical_files = 0;
+    let files = key_files(vault_key)?;
+    for (file_name, content) in &files {
+        let file_path = path.join(file_name);
+        match fs::read(&file_path).await {
+            Ok(existing_content) => {
+                existing_files += 1;
+                if &existing_content == content
+                    && has_permissions(&file_path, permissions)
+                        .await
+                        .with_context(|| format!("Failed to read {}", file_name))?
+                {
+                    identical_files += 1;
+                }
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error).context(format!("Failed to read {}", file_name)),
+        }
+    }
+    Ok(if identical_files == files.len() {
+        KeyChange::Unchanged
+    } else if existing_files == 0 {
+        KeyChange::Added
+    } else {
+        KeyChange::Changed
+    })
+}
+
+async fn has_permissions(path: &Path, permissions: &FilePermissions) -> Result<bool, Error> {
+    let metadata = fs::metadata(path).await?;
+    Ok(metadata.mode() & 0o7777 == permissions.mode
+        && permissions.uid.is_none_or(|uid| uid == metadata.uid())
+        && permissions.gid.is_none_or(|gid| gid == metadata.gid()))
+}
+
+/// Atomically replaces `path` with `content`: the content is written and
+/// synced to a temporary file of the same directory, renamed over `path`, and
+/// the directory is synced so that the rename survives a crash. The mode and
+/// owner are set before the rename, so the content is never exposed.
+pub async fn write_file(
+    path: &Path,
+    content: &[u8],
+    permissions: &FilePermissions,
+) -> Result<(), Error> {
+    let directory = match path.parent() {
+        // A bare file name lives in the current directory
+        Some(directory) if directory.as_os_str().is_empty() => Path::new("."),
+        Some(directory) => directory,
+        None => return Err(anyhow!("{} has no parent directory", path.display())),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", path.display()))?;
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect();
+    let temporary_path = directory.join(format!(".{}.{}.tmp", file_name.to_string_lossy(), suffix));
+
+    let result = async {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(permissions.mode)
+            .open(&temporary_path)
+            .await?;
+        // The umask may have cleared bits of the requested mode
+        fs::set_permissions(
+            &temporary_path,
+            std::fs::Permissions::from_mode(permissions.mode),
+        )
+        .await?;
+        if permissions.uid.is_some() || permissions.gid.is_some() {
+            chown(&temporary_path, permissions.uid, permissions.gid)?;
+        }
+        file.write_all(content).await?;
+        file.sync_all().await?;
+        fs::rename(&temporary_path, path).await?;
+        File::open(directory).await?.sync_all().await?;
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path).await;
+    }
+    result
+}
+
+/// Extensions of the files written for a key, the YAML config first so that
+/// web3signer stops loading a key before its keystore disappears
+const KEY_FILE_EXTENSIONS: [&str; 3] = ["yaml", "json", "password"];
+
+/// Public keys that have files in the key store
+pub fn stored_pubkeys(path: &Path) -> Result<BTreeSet<String>, Error> {
+    let mut pubkeys = BTreeSet::new();
+    for entry in std::fs::read_dir(path)? {
+        let file_name = entry?.file_name();
+        let pubkey = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix("keystore-"))
+            .and_then(|file_name| file_name.rsplit_once('.'))
+            .filter(|(_, extension)| KEY_FILE_EXTENSIONS.contains(extension))
+            .map(|(pubkey, _)| pubkey.to_string());
+        if let Some(pubkey) = pubkey {
+            pubkeys.insert(pubkey);
+        }
+    }
+    Ok(pubkeys)
+}
+
+/// Stored public keys that are not in `pubkeys`
+pub fn stale_pubkeys(path: &Path, pubkeys: &BTreeSet<String>) -> Result<Vec<String>, Error> {
+    let pubkeys: BTreeSet<String> = pubkeys
+        .iter()
+        .map(|pubkey| normalize_pubkey(pubkey))
+        .collect();
+    Ok(stored_pubkeys(path)?
+        .into_iter()
+        .filter(|pubkey| !pubkeys.contains(&normalize_pubkey(pubkey)))
+        .collect())
+}
+
+/// Removes the files of every stored key that is not in `pubkeys`, and
+/// returns the removed public keys. Nothing is removed when there are more
+/// than `max_keys` of them. A dry run only lists them, and warns when a real
+/// run would refuse to remove them.
+pub async fn prune_keys(
+    path: &Path,
+    pubkeys: &BTreeSet<String>,
+    max_keys: usize,
+    dry_run: bool,
+) -> Result<Vec<String>, Error> {
+    let stale_pubkeys = stale_pubkeys(path, pubkeys)?;
+    if dry_run {
+        for pubkey in &stale_pubkeys {
+            info!("Would prune stale key {}", pubkey);
+        }
+        if stale_pubkeys.len() > max_keys {
+            warn!(
+                "A real run would refuse to prune {} keys, more than the {} allowed by prune_max_keys",
+                stale_pubkeys.len(),
+                max_keys
+            );
+        }
+        return Ok(stale_pubkeys);
+    }
+    if stale_pubkeys.len() > max_keys {
+        return Err(anyhow!(
+            "Refusing to prune {} keys, more than the {} allowed by prune_max_keys",
+            stale_pubkeys.len(),
+            max_keys
+        ));
+    }
+
+    for pubkey in &stale_pubkeys {
+        info!("Pruning stale key {}", pubkey);
+        for extension in KEY_FILE_EXTENSIONS {
+            let file_path = path.join(format!("keystore-{}.{}", pubkey, extension));
+            match fs::remove_file(&file_path).await {
+                Ok(_) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => {
+                    return Err(error).context(format!("Failed to remove {}", file_path.display()))
+                }
+            }
+        }
+    }
+    if !stale_pubkeys.is_empty() {
+        File::open(path).await?.sync_all().await?;
+    }
+    Ok(stale_pubkeys)
+}
@@ -0,0 +1,320 @@
+use super::*;
+use crate::eip2335::KdfFunction;
+use crate::keystores::KeystoreVariant;
+use std::path::PathBuf;
+
+const PERMISSIONS: FilePermissions = FilePermissions {
+    mode: 0o600,
+    uid: None,
+    gid: None,
+};
+
+fn temporary_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("vault-loader-test-{}", name));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+#[tokio::test]
+async fn test_write_file() {
+    let directory = temporary_directory("write-file");
+    let path = directory.join("keystore-0x01.password");
+
+    write_file(&path, b"first", &PERMISSIONS).await.unwrap();
+    write_file(&path, b"second", &PERMISSIONS).await.unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+    assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o7777, 0o600);
+
+    let permissions = FilePermissions {
+        mode: 0o640,
+        ..PERMISSIONS
+    };
+    write_file(&path, b"third", &permissions).await.unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o7777, 0o640);
+
+    let files: Vec<_> = std::fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(files, vec!["keystore-0x01.password"]);
+
+    let missing = directory.join("missing").join("keystore-0x01.password");
+    assert!(write_file(&missing, b"content", &PERMISSIONS)
+        .await
+        .is_err());
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_write_file_relative() {
+    let path = Path::new("vault-loader-test-write-file-relative.prom");
+    write_file(path, b"metrics", &PERMISSIONS).await.unwrap();
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "metrics");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_write_vault_key() {
+    let directory = temporary_directory("write-vault-key");
+    let vault_key = VaultKey {
+        pubkey: "0x01".to_owned(),
+        password: Some("password".to_owned()),
+        vkey: Some(r#"{"version": 4}"#.to_owned()),
+        ..Default::default()
+    };
+    write_vault_key(&vault_key, &directory, &PERMISSIONS)
+        .await
+        .unwrap();
+
+    let yaml = std::fs::read_to_string(directory.join("keystore-0x01.yaml")).unwrap();
+    assert!(yaml.contains("keystoreFile: keystore-0x01.json"));
+    assert_eq!(
+        std::fs::read_to_string(directory.join("keystore-0x01.json")).unwrap(),
+        r#"{"version":4}"#
+    );
+    assert_eq!(
+        std::fs::read_to_string(directory.join("keystore-0x01.password")).unwrap(),
+        "password"
+    );
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn test_prepare_key_store() {
+    let directory = temporary_directory("prepare-key-store");
+    std::fs::set_permissions(&directory, std::fs::Permissions::from_mode(0o777)).unwrap();
+    let config = Config {
+        web3signer_key_store_path: directory.clone(),
+        ..Default::default()
+    };
+    assert!(prepare_key_store(&config).is_err());
+
+    let config = Config {
+        web3signer_allow_insecure_key_store: true,
+        ..config
+    };
+    assert!(prepare_key_store(&config).is_ok());
+
+    let config = Config {
+        web3signer_dir_mode: Some(0o750),
+        web3signer_allow_insecure_key_store: false,
+        ..config
+    };
+    assert!(prepare_key_store(&config).is_ok());
+    assert_eq!(
+        std::fs::metadata(&directory).unwrap().mode() & 0o7777,
+        0o750
+    );
+
+    let config = Config {
+        web3signer_key_store_path: directory.join("missing"),
+        ..config
+    };
+    assert!(prepare_key_store(&config).is_err());
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_prune_keys() {
+    let directory = temporary_directory("prune-keys");
+    for file_name in [
+        "keystore-0x01.yaml",
+        "keystore-0x01.json",
+        "keystore-0x01.password",
+        "keystore-0x02.yaml",
+        "keystore-0x03.yaml",
+        "keystore-0x03.json",
+        "keystore-0x03.password",
+        "README.md",
+    ] {
+        std::fs::write(directory.join(file_name), "").unwrap();
+    }
+    assert_eq!(
+        stored_pubkeys(&directory).unwrap(),
+        BTreeSet::from(["0x01".to_string(), "0x02".to_string(), "0x03".to_string()])
+    );
+
+    let pubkeys = BTreeSet::from(["0X01".to_string()]);
+    assert!(prune_keys(&directory, &pubkeys, 1, false).await.is_err());
+
+    // A dry run lists the stale keys even above max_keys
+    let pruned = prune_keys(&directory, &pubkeys, 1, true).await.unwrap();
+    assert_eq!(pruned, vec!["0x02", "0x03"]);
+
+    let pruned = prune_keys(&directory, &pubkeys, 2, true).await.unwrap();
+    assert_eq!(pruned, vec!["0x02", "0x03"]);
+    assert!(directory.join("keystore-0x03.json").exists());
+
+    let pruned = prune_keys(&directory, &pubkeys, 2, false).await.unwrap();
+    assert_eq!(pruned, vec!["0x02", "0x03"]);
+    assert_eq!(
+        stored_pubkeys(&directory).unwrap(),
+        BTreeSet::from(["0x01".to_string()])
+    );
+    assert!(directory.join("README.md").exists());
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_diff_vault_key() {
+    let directory = temporary_directory("diff-vault-key");
+    let vault_key = VaultKey {
+        pubkey: "0x01".to_owned(),
+        password: Some("password".to_owned()),
+        vkey: Some(r#"{"version": 4}"#.to_owned()),
+        ..Default::default()
+    };
+    assert_eq!(
+        diff_vault_key(&vault_key, &directory, &PERMISSIONS)
+            .await
+            .unwrap(),
+        KeyChange::Added
+    );
+
+    write_vault_key(&vault_key, &directory, &PERMISSIONS)
+        .await
+        .unwrap();
+    assert_eq!(
+        diff_vault_key(&vault_key, &directory, &PERMISSIONS)
+            .await
+            .unwrap(),
+        KeyChange::Unchanged
+    );
+
+    // A file whose mode drifted is rewritten
+    std::fs::set_permissions(
+        directory.join("keystore-0x01.yaml"),
+        std::fs::Permissions::from_mode(0o644),
+    )
+    .unwrap();
+    assert_eq!(
+        diff_vault_key(&vault_key, &directory, &PERMISSIONS)
+            .await
+            .unwrap(),
+        KeyChange::Changed
+    );
+
+    let vault_key = VaultKey {
+        password: Some("new password".to_owned()),
+        ..vault_key
+    };
+    assert_eq!(
+        diff_vault_key(&vault_key, &directory, &PERMISSIONS)
+            .await
+            .unwrap(),
+        KeyChange::Changed
+    );
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_read_stored_keystore() {
+    let directory = temporary_directory("read-stored-keystore");
+    assert!(read_stored_keystore(&directory, "0x01").unwrap().is_none());
+
+    let secret =
+        hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+    let keystore = Keystore::encrypt(&secret, "password", KdfFunction::Pbkdf2, 1).unwrap();
+    let vault_key = VaultKey {
+        pubkey: "0x01".to_owned(),
+        password: Some("password".to_owned()),
+        vkey: Some(serde_json::to_string(&keystore).unwrap()),
+        ..Default::default()
+    };
+    write_vault_key(&vault_key, &directory, &PERMISSIONS)
+        .await
+        .unwrap();
+    let stored = read_stored_keystore(&directory, "0x01").unwrap().unwrap();
+    assert_eq!(stored.keystore, keystore);
+    assert_eq!(*stored.password, "password");
+
+    // A raw key wrapped into the stored keystore is left unchanged
+    let mut raw_vault_key = VaultKey {
+        pubkey: "0x01".to_owned(),
+        raw_unencrypted_key: Some(format!("0x{}", hex::encode(&secret))),
+        ..Default::default()
+    };
+    assert!(raw_vault_key
+        .keep_stored_keystore(
+            &stored.keystore,
+            &stored.password,
+            None,
+            KdfFunction::Pbkdf2,
+            1
+        )
+        .unwrap());
+    assert_eq!(
+        diff_vault_key(&raw_vault_key, &directory, &PERMISSIONS)
+            .await
+            .unwrap(),
+        KeyChange::Unchanged
+    );
+
+    std::fs::write(directory.join("keystore-0x01.json"), "{}").unwrap();
+    assert!(read_stored_keystore(&directory, "0x01").unwrap().is_none());
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_keep_stored_raw_key_with_reencrypt_password() {
+    // With encrypt_raw_keys, reencrypt_keystores and reencrypt_password_path
+    // all set, raw keys still get a generated password
+    let directory = temporary_directory("keep-stored-raw-key");
+    let secret =
+        hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+    let mut vault_key = VaultKey {
+        pubkey: "0x01".to_owned(),
+        raw_unencrypted_key: Some(format!("0x{}", hex::encode(&secret))),
+        ..Default::default()
+    };
+    vault_key
+        .encrypt_raw_key("generated", KdfFunction::Pbkdf2, 1)
+        .unwrap();
+    write_vault_key(&vault_key, &directory, &PERMISSIONS)
+        .await
+        .unwrap();
+    let stored = read_stored_keystore(&directory, "0x01").unwrap().unwrap();
+
+    let mut raw_vault_key = VaultKey {
+        pubkey: "0x01".to_owned(),
+        raw_unencrypted_key: Some(format!("0x{}", hex::encode(&secret))),
+        ..Default::default()
+    };
+    assert!(raw_vault_key
+        .keep_stored_keystore(
+            &stored.keystore,
+            &stored.password,
+            Some("reencrypt-password"),
+            KdfFunction::Pbkdf2,
+            1
+        )
+        .unwrap());
+    assert_eq!(
+        diff_vault_key(&raw_vault_key, &directory, &PERMISSIONS)
+            .await
+            .unwrap(),
+        KeyChange::Unchanged
+    );
+
+    // Re-encrypted keystores still follow the password file
+    let mut vault_key = VaultKey {
+        pubkey: "0x01".to_owned(),
+        password: Some("generated".to_owned()),
+        pbkdf2_key: Some(serde_json::to_string(&stored.keystore).unwrap()),
+        ..Default::default()
+    };
+    vault_key
+        .select_keystore(&[KeystoreVariant::Pbkdf2])
+        .unwrap();
+    assert!(!vault_key
+        .keep_stored_keystore(
+            &stored.keystore,
+            &stored.password,
+            Some("reencrypt-password"),
+            KdfFunction::Pbkdf2,
+            1
+        )
+        .unwrap());
+    std::fs::remove_dir_all(directory).unwrap();
+}
@@ -0,0 +1,449 @@
+use crate::config::Config;
+use crate::eip2335::normalize_pubkey;
+use crate::keystores::{VaultKey, Web3signerKeyConfigFormat};
+use crate::vault::tls_client_builder;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use log::{error, info, warn};
+use reqwest::{Client, RequestBuilder, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+#[cfg(test)]
+#[path = "./keymanager_tests.rs"]
+mod keymanager_tests;
+
+/// Where fetched keys are delivered
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputBackend {
+    /// web3signer key store files in `web3signer_key_store_path`
+    #[default]
+    Files,
+    /// Keystores imported through the Keymanager API at `keymanager_url`
+    Keymanager,
+}
+
+/// Outcome of a keystore import reported by the Keymanager API
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Imported,
+    Duplicate,
+    Error,
+}
+
+/// Outcome of a remote key removal reported by the Keymanager API
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteStatus {
+    Deleted,
+    NotFound,
+    Error,
+}
+
+#[derive(Deserialize, Debug)]
+struct StatusResult<S> {
+    status: S,
+    #[serde(default)]
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DataResponse<T> {
+    data: Vec<T>,
+}
+
+/// Key of a validator client signed by a remote signer
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RemoteKey {
+    pub pubkey: String,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub readonly: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct ImportRemoteKey<'a> {
+    pubkey: String,
+    url: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct ImportRemoteKeysRequest<'a> {
+    remote_keys: Vec<ImportRemoteKey<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+struct DeleteRemoteKeysRequest {
+    pubkeys: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ImportRequest<'a> {
+    keystores: Vec<String>,
+    passwords: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slashing_protection: Option<&'a str>,
+}
+
+/// Client of the standard Keymanager API of validator clients
+#[derive(Clone)]
+pub struct KeymanagerClient {
+    http_client: Client,
+    url: Url,
+    token: String,
+}
+
+impl KeymanagerClient {
+    pub fn new(http_client: Client, url: &str, token: &str) -> Result<Self> {
+        let mut url = Url::parse(url)?;
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Ok(KeymanagerClient {
+            http_client,
+            url,
+            token: token.to_string(),
+        })
+    }
+
+    /// Imports the keystores of `vault_keys` in one request, and returns the
+    /// outcome for each of them in the same order.
+    pub async fn import_keystores(
+        &self,
+        vault_keys: &[&VaultKey],
+        slashing_protection: Option<&str>,
+    ) -> Result<Vec<Result<ImportStatus>>> {
+        let mut statuses: Vec<Option<Result<ImportStatus>>> = vec![];
+        let mut request = ImportRequest {
+            keystores: vec![],
+            passwords: vec![],
+            slashing_protection,
+        };
+        for vault_key in vault_keys {
+            match vault_key.to_config() {
+                Ok(Web3signerKeyConfigFormat::Web3signerFileKeystore(config)) => {
+                    request
+                        .keystores
+                        .push(serde_json::to_string(&config.keystore_file_content)?);
+                    request
+                        .passwords
+                        .push(config.keystore_password_file_content);
+                    statuses.push(None);
+                }
+                Ok(Web3signerKeyConfigFormat::Web3signerFileRaw(_)) => statuses.push(Some(Err(
+                    anyhow!("raw keys can only be imported once wrapped by encrypt_raw_keys"),
+                ))),
+                Err(error) => statuses.push(Some(Err(error))),
+            }
+        }
+        if request.keystores.is_empty() {
+            return Ok(statuses.into_iter().flatten().collect());
+        }
+
+        let url = self.url.join("eth/v1/keystores")?;
+        let response: DataResponse<StatusResult<ImportStatus>> = self
+            .send(self.http_client.post(url).json(&request), "import")
+            .await?;
+        if response.data.len() != request.keystores.len() {
+            return Err(anyhow!(
+                "Keymanager reported {} import results for {} keystores",
+                response.data.len(),
+                request.keystores.len()
+            ));
+        }
+
+        let mut results = response.data.into_iter();
+        Ok(statuses
+            .into_iter()
+            .map(|status| match status {
+                Some(status) => status,
+                None => match results.next() {
+                    Some(StatusResult {
+                        status: ImportStatus::Error,
+                        message,
+                    }) => Err(anyhow!("Keymanager import failed: {}", message)),
+                    Some(result) => Ok(result.status),
+                    None => Err(anyhow!("Keymanager import result missing")),
+                },
+            })
+            .collect())
+    }
+
+    /// Imports the keystores of `vault_keys` in batches of `batch_size`, and
+    /// returns the outcome for each public key. A batch that fails as a whole
+    /// fails each of its keys.
+    pub async fn import_vault_keys(
+        &self,
+        vault_keys: &[&VaultKey],
+        batch_size: usize,
+        slashing_protection: Option<&str>,
+    ) -> BTreeMap<String, Result<ImportStatus>> {
+        let mut statuses = BTreeMap::new();
+        for batch in vault_keys.chunks(batch_size.max(1)) {
+            info!(
+                "Importing {} keystores through the Keymanager API",
+                batch.len()
+            );
+            match self.import_keystores(batch, slashing_protection).await {
+                Ok(batch_statuses) => {
+                    for (vault_key, status) in batch.iter().zip(batch_statuses) {
+                        statuses.insert(vault_key.pubkey.clone(), status);
+                    }
+                }
+                Err(error) => {
+                    for vault_key in batch {
+                        statuses.insert(vault_key.pubkey.clone(), Err(anyhow!("{}", error)));
+                    }
+                }
+            }
+        }
+        statuses
+    }
+
+    /// Lists the keys the validator client signs with a remote signer
+    pub async fn remote_keys(&self) -> Result<Vec<RemoteKey>> {
+        let url = self.url.join("eth/v1/remotekeys")?;
+        let response: DataResponse<RemoteKey> = self
+            .send(self.http_client.get(url), "remote keys listing")
+            .await?;
+        Ok(response.data)
+    }
+
+    /// Registers `pubkeys` as remote keys signed by `signer_url`, and returns
+    /// the outcome for each of them in the same order.
+    pub async fn import_remote_keys(
+        &self,
+        pubkeys: &[String],
+        signer_url: &str,
+    ) -> Result<Vec<Result<ImportStatus>>> {
+        let url = self.url.join("eth/v1/remotekeys")?;
+        let request = ImportRemoteKeysRequest {
+            remote_keys: pubkeys
+                .iter()
+                .map(|pubkey| ImportRemoteKey {
+                    pubkey: format!("0x{}", normalize_pubkey(pubkey)),
+                    url: signer_url,
+                })
+                .collect(),
+        };
+        let response: DataResponse<StatusResult<ImportStatus>> = self
+            .send(
+                self.http_client.post(url).json(&request),
+                "remote keys import",
+            )
+            .await?;
+        statuses_of(response.data, pubkeys.len(), ImportStatus::Error)
+    }
+
+    /// Removes the remote keys of `pubkeys`, and returns the outcome for each
+    /// of them in the same order.
+    pub async fn delete_remote_keys(
+        &self,
+        pubkeys: &[String],
+    ) -> Result<Vec<Result<DeleteStatus>>> {
+        let url = self.url.join("eth/v1/remotekeys")?;
+        let request = DeleteRemoteKeysRequest {
+            pubkeys: pubkeys
+                .iter()
+                .map(|pubkey| format!("0x{}", normalize_pubkey(pubkey)))
+                .collect(),
+        };
+        let response: DataResponse<StatusResult<DeleteStatus>> = self
+            .send(
+                self.http_client.delete(url).json(&request),
+                "remote keys removal",
+            )
+            .await?;
+        statuses_of(response.data, pubkeys.len(), DeleteStatus::Error)
+    }
+
+    /// Registers the `pubkeys` that are not remote keys yet with `signer_url`.
+    /// With `prune`, also removes the remote keys signed by `signer_url` that
+    /// are not in `retained_pubkeys`.
+    pub async fn sync_remote_keys(
+        &self,
+        pubkeys: &BTreeSet<String>,
+        retained_pubkeys: &BTreeSet<String>,
+        signer_url: &str,
+        prune: bool,
+    ) -> Result<()> {
+        let signer_url = signer_url.trim_end_matches('/');
+        let retained_pubkeys: BTreeSet<String> = retained_pubkeys
+            .iter()
+            .map(|p| normalize_pubkey(p))
+            .collect();
+        let mut registered_pubkeys = BTreeSet::new();
+        let mut stale_pubkeys = vec![];
+        for remote_key in self.remote_keys().await? {
+            let pubkey = normalize_pubkey(&remote_key.pubkey);
+            if remote_key.url.trim_end_matches('/') != signer_url {
+                warn!(
+                    "Remote key {} is signed by {}, not {}",
+                    remote_key.pubkey, remote_key.url, signer_url
+                );
+            } else if prune && !remote_key.readonly && !retained_pubkeys.contains(&pubkey) {
+                stale_pubkeys.push(pubkey.clone());
+            }
+            registered_pubkeys.insert(pubkey);
+        }
+        let new_pubkeys: Vec<String> = pubkeys
+            .iter()
+            .map(|p| normalize_pubkey(p))
+            .filter(|pubkey| !registered_pubkeys.contains(pubkey))
+            .collect();
+
+        let mut failures = 0;
+        if !new_pubkeys.is_empty() {
+            info!(
+                "Registering {} remote keys at {}",
+                new_pubkeys.len(),
+                self.url
+            );
+            let statuses = self.import_remote_keys(&new_pubkeys, signer_url).await?;
+            for (pubkey, status) in new_pubkeys.iter().zip(statuses) {
+                match status {
+                    Ok(ImportStatus::Imported) => info!("Remote key registered for: 0x{}", pubkey),
+                    Ok(_) => info!("Remote key already registered for: 0x{}", pubkey),
+                    Err(error) => {
+                        error!("Failed to register remote key for 0x{}: {}", pubkey, error);
+                        failures += 1;
+                    }
+                }
+            }
+        }
+        if !stale_pubkeys.is_empty() {
+            info!(
+                "Removing {} stale remote keys at {}",
+                stale_pubkeys.len(),
+                self.url
+            );
+            let statuses = self.delete_remote_keys(&stale_pubkeys).await?;
+            for (pubkey, status) in stale_pubkeys.iter().zip(statuses) {
+                match status {
+                    Ok(_) => info!("Remote key removed for: 0x{}", pubkey),
+                    Err(error) => {
+                        error!("Failed to remove remote key for 0x{}: {}", pubkey, error);
+                        failures += 1;
+                    }
+                }
+            }
+        }
+        if failures > 0 {
+            return Err(anyhow!(
+                "Failed to register or remove {} remote keys at {}",
+                failures,
+                self.url
+            ));
+        }
+        Ok(())
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder, action: &str) -> Result<T> {
+        let response = request.bearer_auth(&self.token).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            error!("Keymanager {} failed with status {}", action, status);
+            return Err(anyhow!(
+                "Keymanager {} failed with status {}",
+                action,
+                status
+            ));
+        }
+        response
+            .json::<T>()
+            .await
+            .with_context(|| format!("Failed to parse Keymanager {} response", action))
+    }
+}
+
+/// Turns the results of a Keymanager API request into one outcome per item,
+/// failing when their count does not match the `expected` one.
+fn statuses_of<S: PartialEq>(
+    results: Vec<StatusResult<S>>,
+    expected: usize,
+    error_status: S,
+) -> Result<Vec<Result<S>>> {
+    if results.len() != expected {
+        return Err(anyhow!(
+            "Keymanager reported {} results for {} keys",
+            results.len(),
+            expected
+        ));
+    }
+    Ok(results
+        .into_iter()
+        .map(|result| {
+            if result.status == error_status {
+                Err(anyhow!("{}", result.message))
+            } else {
+                Ok(result.status)
+            }
+        })
+        .collect())
+}
+
+pub fn build_keymanager_client(config: &Config) -> Result<Option<KeymanagerClient>> {
+    if config.output_backend != OutputBackend::Keymanager {
+        return Ok(None);
+    }
+    let url = config
+        .keymanager_url
+        .as_ref()
+        .ok_or_else(|| anyhow!("keymanager_url must be set with the keymanager backend"))?;
+    let token_path = config
+        .keymanager_token_path
+        .as_ref()
+        .ok_or_else(|| anyhow!("keymanager_token_path must be set with the keymanager backend"))?;
+    let http_client = tls_client_builder(config)?.build()?;
+    Ok(Some(KeymanagerClient::new(
+        http_client,
+        url,
+        &read_token(token_path)?,
+    )?))
+}
+
+/// Builds a client for each validator client to register remote keys with
+pub fn build_remotekeys_clients(config: &Config) -> Result<Vec<KeymanagerClient>> {
+    if config.remotekeys_urls.is_empty() {
+        return Ok(vec![]);
+    }
+    let http_client = tls_client_builder(config)?.build()?;
+    config
+        .remotekeys_urls
+        .iter()
+        .zip(&config.remotekeys_token_paths)
+        .map(|(url, token_path)| {
+            KeymanagerClient::new(http_client.clone(), url, &read_token(token_path)?)
+        })
+        .collect()
+}
+
+fn read_token(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(token) => Ok(token.trim().to_string()),
+        Err(error) => {
+            error!("Failed to read Keymanager API token: {}", error);
+            Err(error).context("Failed to read Keymanager API token")
+        }
+    }
+}
+
+/// Reads the EIP-3076 slashing protection interchange uploaded with keystores
+pub fn parse_slashing_protection(config: &Config) -> Result<Option<String>> {
+    match &config.keymanager_slashing_protection_path {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(slashing_protection) => Ok(Some(slashing_protection)),
+            Err(error) => {
+                error!("Failed to read slashing protection file: {}", error);
+                Err(error).context("Failed to read slashing protection file")
+            }
+        },
+        None => Ok(None),
+    }
+}
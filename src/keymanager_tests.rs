@@ -0,0 +1,162 @@
+use super::*;
+use crate::eip2335::{KdfFunction, Keystore};
+use serde_json::json;
+use wiremock::matchers::{body_partial_json, header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const SECRET: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+fn vault_key() -> VaultKey {
+    let secret = hex::decode(SECRET).unwrap();
+    let keystore = Keystore::encrypt(&secret, "password", KdfFunction::Pbkdf2, 1).unwrap();
+    VaultKey {
+        pubkey: format!("0x{}", keystore.pubkey),
+        vkey: Some(serde_json::to_string(&keystore).unwrap()),
+        password: Some("password".to_string()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_import_keystores() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/eth/v1/keystores"))
+        .and(header("Authorization", "Bearer token"))
+        .and(body_partial_json(
+            json!({"passwords": ["password", "password"]}),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": [
+            {"status": "imported", "message": ""},
+            {"status": "error", "message": "invalid keystore"},
+        ]})))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let keymanager_client =
+        KeymanagerClient::new(Client::new(), &mock_server.uri(), "token").unwrap();
+    let vault_key = vault_key();
+    let raw_vault_key = VaultKey {
+        pubkey: "0x01".to_string(),
+        raw_unencrypted_key: Some(format!("0x{}", SECRET)),
+        ..Default::default()
+    };
+    let statuses = keymanager_client
+        .import_keystores(&[&vault_key, &raw_vault_key, &vault_key], None)
+        .await
+        .unwrap();
+    assert_eq!(statuses.len(), 3);
+    assert_eq!(statuses[0].as_ref().unwrap(), &ImportStatus::Imported);
+    assert!(statuses[1].is_err());
+    assert!(statuses[2].is_err());
+}
+
+#[tokio::test]
+async fn test_import_vault_keys() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/keymanager/eth/v1/keystores"))
+        .and(body_partial_json(json!({"slashing_protection": "{}"})))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": [
+            {"status": "duplicate", "message": ""},
+        ]})))
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+
+    let url = format!("{}/keymanager", mock_server.uri());
+    let keymanager_client = KeymanagerClient::new(Client::new(), &url, "token").unwrap();
+    let vault_key = vault_key();
+    let other_vault_key = VaultKey {
+        pubkey: "0x02".to_string(),
+        ..vault_key.clone()
+    };
+    let statuses = keymanager_client
+        .import_vault_keys(&[&vault_key, &other_vault_key], 1, Some("{}"))
+        .await;
+    assert_eq!(statuses.len(), 2);
+    assert!(statuses
+        .values()
+        .all(|status| matches!(status, Ok(ImportStatus::Duplicate))));
+
+    let keymanager_client =
+        KeymanagerClient::new(Client::new(), &mock_server.uri(), "token").unwrap();
+    let statuses = keymanager_client
+        .import_vault_keys(&[&vault_key, &other_vault_key], 20, None)
+        .await;
+    assert!(statuses.values().all(|status| status.is_err()));
+}
+
+#[tokio::test]
+async fn test_sync_remote_keys() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/eth/v1/remotekeys"))
+        .and(header("Authorization", "Bearer token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": [
+            {"pubkey": "0x01", "url": "http://web3signer:9000/", "readonly": false},
+            {"pubkey": "0x02", "url": "http://web3signer:9000", "readonly": false},
+            {"pubkey": "0x03", "url": "http://other-signer:9000", "readonly": false},
+        ]})))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/eth/v1/remotekeys"))
+        .and(body_partial_json(json!({"remote_keys": [
+            {"pubkey": "0x04", "url": "http://web3signer:9000"},
+        ]})))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": [
+            {"status": "imported", "message": ""},
+        ]})))
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path("/eth/v1/remotekeys"))
+        .and(body_partial_json(json!({"pubkeys": ["0x02"]})))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": [
+            {"status": "deleted", "message": ""},
+        ]})))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let keymanager_client =
+        KeymanagerClient::new(Client::new(), &mock_server.uri(), "token").unwrap();
+    let pubkeys = BTreeSet::from(["0x01".to_string(), "0X04".to_string()]);
+    let retained_pubkeys = BTreeSet::from(["0x01".to_string(), "0x04".to_string()]);
+    keymanager_client
+        .sync_remote_keys(&pubkeys, &retained_pubkeys, "http://web3signer:9000", false)
+        .await
+        .unwrap();
+    keymanager_client
+        .sync_remote_keys(&pubkeys, &retained_pubkeys, "http://web3signer:9000/", true)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_sync_remote_keys_error() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/eth/v1/remotekeys"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": []})))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/eth/v1/remotekeys"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": [
+            {"status": "error", "message": "invalid url"},
+        ]})))
+        .mount(&mock_server)
+        .await;
+
+    let keymanager_client =
+        KeymanagerClient::new(Client::new(), &mock_server.uri(), "token").unwrap();
+    let pubkeys = BTreeSet::from(["0x01".to_string()]);
+    assert!(keymanager_client
+        .sync_remote_keys(&pubkeys, &pubkeys, "http://web3signer:9000", true)
+        .await
+        .is_err());
+}
@@ -1,8 +1,16 @@
 use anyhow::{anyhow, Error, Result};
 use base64::{engine::general_purpose, Engine as _};
+use clap::ValueEnum;
 use enum_dispatch::enum_dispatch;
+use log::warn;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::fmt;
+use zeroize::Zeroizing;
+
+use crate::eip2335::{bls_pubkey, normalize_pubkey, KdfFunction, KdfParams, Keystore};
+use crate::vault::SecretVersion;
 
 #[cfg(test)]
 #[path = "./keystores_tests.rs"]
@@ -18,11 +26,121 @@ pub struct VaultKey {
     pub scrypt_key: Option<String>,
     pub raw_unencrypted_key: Option<String>,
     pub realm: Option<String>,
+    #[serde(skip_deserializing)]
+    pub version: Option<SecretVersion>,
+    #[serde(skip_deserializing)]
+    pub keystore_variant: Option<KeystoreVariant>,
+}
+
+/// Secret field holding an EIP-2335 keystore
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeystoreVariant {
+    /// `pbkdf2_key`, the fastest to decrypt when web3signer starts
+    Pbkdf2,
+    /// `scrypt_key`
+    Scrypt,
+    /// `vkey`
+    Vkey,
+}
+
+impl KeystoreVariant {
+    /// Order in which keystores are picked when no preference is configured
+    pub fn default_preference() -> Vec<Self> {
+        vec![
+            KeystoreVariant::Pbkdf2,
+            KeystoreVariant::Scrypt,
+            KeystoreVariant::Vkey,
+        ]
+    }
+
+    pub fn field(&self) -> &'static str {
+        match self {
+            KeystoreVariant::Pbkdf2 => "pbkdf2_key",
+            KeystoreVariant::Scrypt => "scrypt_key",
+            KeystoreVariant::Vkey => "vkey",
+        }
+    }
+}
+
+impl fmt::Display for KeystoreVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreVariant::Pbkdf2 => write!(f, "pbkdf2"),
+            KeystoreVariant::Scrypt => write!(f, "scrypt"),
+            KeystoreVariant::Vkey => write!(f, "vkey"),
+        }
+    }
+}
+
+/// Error for a secret that must not be loaded, as opposed to a transient
+/// failure that is worth retrying.
+#[derive(Debug, PartialEq)]
+pub struct RejectedVaultKey(pub String);
+
+impl fmt::Display for RejectedVaultKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RejectedVaultKey {}
+
+/// Names of the secret fields holding each part of a `VaultKey`
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub struct VaultKeyFields {
+    pub vkey: String,
+    pub password: String,
+    pub pbkdf2_key: String,
+    pub scrypt_key: String,
+    pub raw_unencrypted_key: String,
+    pub realm: String,
+}
+
+impl Default for VaultKeyFields {
+    fn default() -> Self {
+        VaultKeyFields {
+            vkey: "vkey".to_string(),
+            password: "password".to_string(),
+            pbkdf2_key: "pbkdf2_key".to_string(),
+            scrypt_key: "scrypt_key".to_string(),
+            raw_unencrypted_key: "raw_unencrypted_key".to_string(),
+            realm: "realm".to_string(),
+        }
+    }
+}
+
+impl VaultKeyFields {
+    /// Renames the configured secret fields to the `VaultKey` field names,
+    /// dropping every other field.
+    pub fn rename(&self, object: Value) -> Value {
+        let mut renamed = Map::new();
+        if let Value::Object(mut object) = object {
+            for (field, name) in [
+                ("vkey", &self.vkey),
+                ("password", &self.password),
+                ("pbkdf2_key", &self.pbkdf2_key),
+                ("scrypt_key", &self.scrypt_key),
+                ("raw_unencrypted_key", &self.raw_unencrypted_key),
+                ("realm", &self.realm),
+            ] {
+                if let Some(value) = object.remove(name) {
+                    renamed.insert(field.to_string(), value);
+                }
+            }
+        }
+        Value::Object(renamed)
+    }
 }
 
 impl VaultKey {
-    pub fn new(object: Value, pubkey: &str) -> Result<Self, anyhow::Error> {
-        let mut vault_key: Self = serde_json::from_value(object)?;
+    pub fn new(
+        object: Value,
+        pubkey: &str,
+        fields: &VaultKeyFields,
+    ) -> Result<Self, anyhow::Error> {
+        let mut vault_key: Self = serde_json::from_value(fields.rename(object))?;
         vault_key.pubkey = pubkey.to_string();
         if ((vault_key.vkey.is_some()
             || vault_key.pbkdf2_key.is_some()
@@ -35,6 +153,308 @@ impl VaultKey {
         Err(anyhow!("Invalid vault key"))
     }
 
+    /// Fields holding Vault Transit ciphertext that must be decrypted before
+    /// the key can be written. `raw_unencrypted_key` is only considered when
+    /// `include_raw_unencrypted_key` is set.
+    pub fn transit_ciphertexts_mut(
+        &mut self,
+        include_raw_unencrypted_key: bool,
+    ) -> Vec<&mut String> {
+        let mut fields = vec![];
+        if let Some(password) = self.password.as_mut() {
+            fields.push(password);
+        }
+        if include_raw_unencrypted_key {
+            if let Some(raw_unencrypted_key) = self.raw_unencrypted_key.as_mut() {
+                fields.push(raw_unencrypted_key);
+            }
+        }
+        fields
+            .into_iter()
+            .filter(|field| is_transit_ciphertext(field))
+            .collect()
+    }
+
+    /// Checks that the secret belongs to `pubkey`. A raw key is checked by
+    /// deriving its public key, the keystore that `to_config` writes by its
+    /// `pubkey` field or, when `decrypt_keystore` is set, by decrypting it and
+    /// deriving its public key. Other keystores are not checked.
+    pub fn verify_pubkey(&self, decrypt_keystore: bool) -> Result<(), Error> {
+        let expected_pubkey = normalize_pubkey(&self.pubkey);
+        if let Some(raw_unencrypted_key) = &self.raw_unencrypted_key {
+            let secret = Zeroizing::new(hex::decode(
+                raw_unencrypted_key.trim().trim_start_matches("0x"),
+            )?);
+            let pubkey = bls_pubkey(&secret)?;
+            if normalize_pubkey(&pubkey) != expected_pubkey {
+                return Err(anyhow!(RejectedVaultKey(format!(
+                    "raw_unencrypted_key belongs to {}",
+                    pubkey
+                ))));
+            }
+            return Ok(());
+        }
+
+        let (variant, keystore) = self
+            .selected_keystore()
+            .ok_or_else(|| anyhow!("Invalid vault key"))?;
+        let keystore = parse_keystore(keystore)?;
+        if keystore.pubkey.is_empty() {
+            warn!(
+                "{} of {} does not record its public key",
+                variant.field(),
+                self.pubkey
+            );
+        } else if normalize_pubkey(&keystore.pubkey) != expected_pubkey {
+            return Err(anyhow!(RejectedVaultKey(format!(
+                "{} belongs to 0x{}",
+                variant.field(),
+                normalize_pubkey(&keystore.pubkey)
+            ))));
+        }
+
+        if decrypt_keystore {
+            let password = self.password.as_deref().unwrap_or_default();
+            let pubkey = bls_pubkey(&keystore.decrypt(password)?)?;
+            if normalize_pubkey(&pubkey) != expected_pubkey {
+                return Err(anyhow!(RejectedVaultKey(format!(
+                    "keystore decrypts to the secret key of {}",
+                    pubkey
+                ))));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the keystore that `to_config` writes is a well-formed
+    /// EIP-2335 keystore and, when `verify_password` is set, that the password
+    /// decrypts it. Other keystores are not checked.
+    pub fn validate_keystores(&self, verify_password: bool) -> Result<(), Error> {
+        if self.raw_unencrypted_key.is_some() {
+            return Ok(());
+        }
+        if let Some((variant, keystore)) = self.selected_keystore() {
+            let keystore = parse_keystore(keystore)
+                .and_then(|keystore| keystore.validate().map(|_| keystore))
+                .map_err(|e| {
+                    anyhow!(RejectedVaultKey(format!(
+                        "{} is not a valid EIP-2335 keystore: {}",
+                        variant.field(),
+                        e
+                    )))
+                })?;
+            if verify_password {
+                if let Some(password) = &self.password {
+                    keystore.verify_password(password).map_err(|e| {
+                        anyhow!(RejectedVaultKey(format!(
+                            "password does not decrypt {}: {}",
+                            variant.field(),
+                            e
+                        )))
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Picks the first keystore of `preference` present in the secret as the
+    /// one `to_config` writes. Only that keystore is later validated and
+    /// verified, so malformed keystores of the secret and keystores that
+    /// disagree on their public key only draw a warning here. Raw keys need
+    /// no keystore.
+    pub fn select_keystore(
+        &mut self,
+        preference: &[KeystoreVariant],
+    ) -> Result<Option<KeystoreVariant>, Error> {
+        let mut pubkeys = BTreeSet::new();
+        for (variant, keystore) in self.keystores() {
+            if let Some(keystore) = keystore {
+                match parse_keystore(keystore).and_then(|keystore| {
+                    keystore.validate()?;
+                    Ok(keystore)
+                }) {
+                    Ok(keystore) if !keystore.pubkey.is_empty() => {
+                        pubkeys.insert(normalize_pubkey(&keystore.pubkey));
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!(
+                        "{} of {} is not a valid EIP-2335 keystore: {}",
+                        variant.field(),
+                        self.pubkey,
+                        e
+                    ),
+                }
+            }
+        }
+        if pubkeys.len() > 1 {
+            warn!(
+                "Keystores of {} disagree on their public key: 0x{}",
+                self.pubkey,
+                pubkeys.into_iter().collect::<Vec<_>>().join(", 0x")
+            );
+        }
+
+        if self.raw_unencrypted_key.is_some() {
+            self.keystore_variant = None;
+            return Ok(None);
+        }
+        match preference
+            .iter()
+            .find(|variant| self.keystore(**variant).is_some())
+        {
+            Some(variant) => {
+                self.keystore_variant = Some(*variant);
+                Ok(Some(*variant))
+            }
+            None => Err(anyhow!(RejectedVaultKey(format!(
+                "no keystore among {}",
+                preference
+                    .iter()
+                    .map(|variant| variant.field())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )))),
+        }
+    }
+
+    /// Decrypts the keystore that `to_config` writes with the Vault password
+    /// and encrypts it again with `password`, which replaces the Vault
+    /// password. The new keystore is stored under the variant of `kdf` and
+    /// selected, and the other keystores, which the new password cannot
+    /// decrypt, are dropped. Raw keys are left untouched.
+    pub fn reencrypt_keystore(
+        &mut self,
+        password: &str,
+        kdf: KdfFunction,
+        cost: u32,
+    ) -> Result<(), Error> {
+        if self.raw_unencrypted_key.is_some() {
+            return Ok(());
+        }
+        let secret = self.secret()?;
+        self.pbkdf2_key = None;
+        self.scrypt_key = None;
+        self.vkey = None;
+        self.store_keystore(Keystore::encrypt(&secret, password, kdf, cost)?, password)
+    }
+
+    /// Wraps the raw key into a keystore encrypted with `password`, so that
+    /// `to_config` writes a keystore instead of the cleartext key.
+    pub fn encrypt_raw_key(
+        &mut self,
+        password: &str,
+        kdf: KdfFunction,
+        cost: u32,
+    ) -> Result<(), Error> {
+        let raw_unencrypted_key = match self.raw_unencrypted_key.take() {
+            Some(raw_unencrypted_key) => raw_unencrypted_key,
+            None => return Ok(()),
+        };
+        let raw_unencrypted_key = Zeroizing::new(raw_unencrypted_key);
+        let secret = Zeroizing::new(hex::decode(
+            raw_unencrypted_key.trim().trim_start_matches("0x"),
+        )?);
+        self.store_keystore(Keystore::encrypt(&secret, password, kdf, cost)?, password)
+    }
+
+    /// Keeps `stored_keystore`, the keystore that the key store already holds
+    /// for this key, in place of the secret when it is encrypted with `kdf`
+    /// and `cost`, with `password` when one is given for a keystore, and
+    /// decrypts with `stored_password` to the same secret. Raw keys are always
+    /// encrypted with a generated password, so `password` does not apply to
+    /// them. Encrypting again would write a fresh salt, IV and password on
+    /// every run. Returns whether it was kept.
+    pub fn keep_stored_keystore(
+        &mut self,
+        stored_keystore: &Keystore,
+        stored_password: &str,
+        password: Option<&str>,
+        kdf: KdfFunction,
+        cost: u32,
+    ) -> Result<bool, Error> {
+        if stored_keystore.kdf() != Some((kdf, cost))
+            || (self.raw_unencrypted_key.is_none()
+                && password.is_some_and(|password| password != stored_password))
+        {
+            return Ok(false);
+        }
+        match stored_keystore.decrypt(stored_password) {
+            Ok(stored_secret) if stored_secret == self.secret()? => {}
+            _ => return Ok(false),
+        }
+        self.raw_unencrypted_key.take().map(Zeroizing::new);
+        self.pbkdf2_key = None;
+        self.scrypt_key = None;
+        self.vkey = None;
+        self.store_keystore(stored_keystore.clone(), stored_password)?;
+        Ok(true)
+    }
+
+    /// Secret key that `to_config` writes, the keystore being decrypted with
+    /// the Vault password
+    fn secret(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
+        if let Some(raw_unencrypted_key) = &self.raw_unencrypted_key {
+            return Ok(Zeroizing::new(hex::decode(
+                raw_unencrypted_key.trim().trim_start_matches("0x"),
+            )?));
+        }
+        let (_, keystore) = self
+            .selected_keystore()
+            .ok_or_else(|| anyhow!("Invalid vault key"))?;
+        let vault_password = self
+            .password
+            .as_deref()
+            .ok_or_else(|| anyhow!("Invalid vault key"))?;
+        parse_keystore(keystore)?.decrypt(vault_password)
+    }
+
+    /// Stores a keystore encrypted by vault-loader under the variant of its
+    /// KDF, and selects it
+    fn store_keystore(&mut self, keystore: Keystore, password: &str) -> Result<(), Error> {
+        let json = Some(serde_json::to_string(&keystore)?);
+        let variant = match keystore.crypto.kdf.params {
+            KdfParams::Pbkdf2 { .. } => {
+                self.pbkdf2_key = json;
+                KeystoreVariant::Pbkdf2
+            }
+            KdfParams::Scrypt { .. } => {
+                self.scrypt_key = json;
+                KeystoreVariant::Scrypt
+            }
+        };
+        self.keystore_variant = Some(variant);
+        self.password = Some(password.to_string());
+        Ok(())
+    }
+
+    fn keystore(&self, variant: KeystoreVariant) -> Option<&String> {
+        match variant {
+            KeystoreVariant::Pbkdf2 => self.pbkdf2_key.as_ref(),
+            KeystoreVariant::Scrypt => self.scrypt_key.as_ref(),
+            KeystoreVariant::Vkey => self.vkey.as_ref(),
+        }
+    }
+
+    fn keystores(&self) -> [(KeystoreVariant, &Option<String>); 3] {
+        [
+            (KeystoreVariant::Pbkdf2, &self.pbkdf2_key),
+            (KeystoreVariant::Scrypt, &self.scrypt_key),
+            (KeystoreVariant::Vkey, &self.vkey),
+        ]
+    }
+
+    /// Keystore that `to_config` writes: the one picked by `select_keystore`,
+    /// or else the first of the default preference
+    fn selected_keystore(&self) -> Option<(KeystoreVariant, &String)> {
+        match self.keystore_variant {
+            Some(variant) => self.keystore(variant).map(|keystore| (variant, keystore)),
+            None => KeystoreVariant::default_preference()
+                .into_iter()
+                .find_map(|variant| self.keystore(variant).map(|keystore| (variant, keystore))),
+        }
+    }
+
     pub fn to_config(&self) -> Result<Web3signerKeyConfigFormat, Error> {
         match &self.raw_unencrypted_key {
             Some(raw_unencrypted_key) => Ok(Web3signerKeyConfigFormat::from(Web3signerFileRaw {
@@ -43,47 +463,20 @@ impl VaultKey {
                 private_key: raw_unencrypted_key.to_string(),
                 ..Default::default()
             })),
-            None => {
-                if let (Some(pbkdf2_key), Some(password)) = (&self.pbkdf2_key, &self.password) {
-                    Ok(Web3signerKeyConfigFormat::from(Web3signerFileKeystore {
-                        pubkey: self.pubkey.to_string(),
-                        filename: format!("keystore-{}.yaml", self.pubkey),
-                        keystore_file: format!("keystore-{}.json", self.pubkey),
-                        keystore_file_content: serde_json::from_str(
-                            base64_decode(pbkdf2_key)?.as_str(),
-                        )?,
-                        keystore_password_file: format!("keystore-{}.password", self.pubkey),
-                        keystore_password_file_content: password.to_string(),
-                        ..Default::default()
-                    }))
-                } else if let (Some(scrypt_key), Some(password)) =
-                    (&self.scrypt_key, &self.password)
-                {
-                    Ok(Web3signerKeyConfigFormat::from(Web3signerFileKeystore {
-                        pubkey: self.pubkey.to_string(),
-                        filename: format!("keystore-{}.yaml", self.pubkey),
-                        keystore_file: format!("keystore-{}.json", self.pubkey),
-                        keystore_file_content: serde_json::from_str(
-                            base64_decode(scrypt_key)?.as_str(),
-                        )?,
-                        keystore_password_file: format!("keystore-{}.password", self.pubkey),
-                        keystore_password_file_content: password.to_string(),
-                        ..Default::default()
-                    }))
-                } else if let (Some(vkey), Some(password)) = (&self.vkey, &self.password) {
+            None => match (self.selected_keystore(), &self.password) {
+                (Some((_, keystore)), Some(password)) => {
                     Ok(Web3signerKeyConfigFormat::from(Web3signerFileKeystore {
                         pubkey: self.pubkey.to_string(),
                         filename: format!("keystore-{}.yaml", self.pubkey),
                         keystore_file: format!("keystore-{}.json", self.pubkey),
-                        keystore_file_content: serde_json::from_str(base64_decode(vkey)?.as_str())?,
+                        keystore_file_content: decode_keystore(keystore)?,
                         keystore_password_file: format!("keystore-{}.password", self.pubkey),
                         keystore_password_file_content: password.to_string(),
                         ..Default::default()
                     }))
-                } else {
-                    Err(anyhow!("Invalid vault key"))
                 }
-            }
+                _ => Err(anyhow!("Invalid vault key")),
+            },
         }
     }
 }
@@ -173,6 +566,28 @@ impl Web3signerKeyConfig for Web3signerFileRaw {
     }
 }
 
+/// Whether `value` is a Vault Transit ciphertext such as `vault:v1:...`
+pub fn is_transit_ciphertext(value: &str) -> bool {
+    value
+        .strip_prefix("vault:v")
+        .and_then(|rest| rest.split_once(':'))
+        .is_some_and(|(version, _)| {
+            !version.is_empty() && version.chars().all(|c| c.is_ascii_digit())
+        })
+}
+
+/// Parses a keystore stored either as base64-encoded or as plain JSON
+fn decode_keystore(input: &str) -> Result<Value, Error> {
+    if input.trim_start().starts_with('{') {
+        return Ok(serde_json::from_str(input)?);
+    }
+    Ok(serde_json::from_str(base64_decode(input)?.as_str())?)
+}
+
+fn parse_keystore(input: &str) -> Result<Keystore, Error> {
+    Ok(serde_json::from_value(decode_keystore(input)?)?)
+}
+
 fn base64_decode(input: &str) -> Result<String, Error> {
     let bytes = general_purpose::STANDARD.decode(input.as_bytes())?;
     let decoded = std::str::from_utf8(&bytes)?.to_string();
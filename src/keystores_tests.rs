@@ -27,7 +27,8 @@ fn test_vault_key_new_full() {
         ..Default::default()
     };
 
-    let vault_key_result = VaultKey::new(vault_key_json.unwrap(), PUBKEY);
+    let vault_key_result =
+        VaultKey::new(vault_key_json.unwrap(), PUBKEY, &VaultKeyFields::default());
     assert!(vault_key_result.is_ok());
     assert_eq!(vault_key_result.as_ref().unwrap(), &expected_vault_key);
 
@@ -66,9 +67,12 @@ fn test_vault_key_new_partial_pbkdf2() {
         realm: None,
         scrypt_key: None,
         raw_unencrypted_key: None,
+        version: None,
+        keystore_variant: None,
     };
 
-    let vault_key_result = VaultKey::new(vault_key_json.unwrap(), PUBKEY);
+    let vault_key_result =
+        VaultKey::new(vault_key_json.unwrap(), PUBKEY, &VaultKeyFields::default());
     assert!(vault_key_result.is_ok());
     assert_eq!(vault_key_result.as_ref().unwrap(), &expected_vault_key);
 
@@ -110,9 +114,12 @@ fn test_vault_key_new_partial_vkey() {
         realm: None,
         scrypt_key: None,
         raw_unencrypted_key: None,
+        version: None,
+        keystore_variant: None,
     };
 
-    let vault_key_result = VaultKey::new(vault_key_json.unwrap(), PUBKEY);
+    let vault_key_result =
+        VaultKey::new(vault_key_json.unwrap(), PUBKEY, &VaultKeyFields::default());
     assert!(vault_key_result.is_ok());
     assert_eq!(vault_key_result.as_ref().unwrap(), &expected_vault_key);
 
@@ -144,7 +151,7 @@ fn test_vault_key_new_invalid() {
         }"#,
     );
     assert!(vault_key_json.is_ok());
-    let vault_key_result = VaultKey::new(vault_key_json.unwrap(), "0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2");
+    let vault_key_result = VaultKey::new(vault_key_json.unwrap(), "0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2", &VaultKeyFields::default());
     assert!(vault_key_result.is_err());
 }
 
@@ -156,7 +163,7 @@ fn test_vault_key_new_partial_invalid_vkey() {
         }"#,
     );
     assert!(vault_key_json.is_ok());
-    let vault_key_result = VaultKey::new(vault_key_json.unwrap(), "0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2");
+    let vault_key_result = VaultKey::new(vault_key_json.unwrap(), "0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2", &VaultKeyFields::default());
     assert!(vault_key_result.is_err());
 }
 
@@ -168,6 +175,302 @@ fn test_vault_key_new_partial_invalid_password() {
         }"#,
     );
     assert!(vault_key_json.is_ok());
-    let vault_key_result = VaultKey::new(vault_key_json.unwrap(), "0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2");
+    let vault_key_result = VaultKey::new(vault_key_json.unwrap(), "0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2", &VaultKeyFields::default());
     assert!(vault_key_result.is_err());
 }
+
+#[test]
+fn test_vault_key_new_custom_fields() {
+    let vault_key_json = serde_json::json!({
+        "keystore": r#"{"crypto": {}, "pubkey": "8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2", "version": 4}"#,
+        "passphrase": "password",
+        "vkey": "ignored"
+    });
+    let fields = VaultKeyFields {
+        vkey: "keystore".to_string(),
+        password: "passphrase".to_string(),
+        ..Default::default()
+    };
+
+    let vault_key = VaultKey::new(vault_key_json, PUBKEY, &fields).unwrap();
+    assert_eq!(vault_key.password, Some("password".to_owned()));
+    assert!(vault_key.vkey.as_ref().unwrap().starts_with("{\"crypto\""));
+
+    match vault_key.to_config().unwrap() {
+        Web3signerKeyConfigFormat::Web3signerFileKeystore(config) => {
+            assert_eq!(config.keystore_file_content["version"], 4);
+            assert_eq!(config.keystore_password_file_content, "password");
+        }
+        Web3signerKeyConfigFormat::Web3signerFileRaw(_) => panic!("expected a keystore"),
+    }
+}
+
+#[test]
+fn test_transit_ciphertexts() {
+    assert!(is_transit_ciphertext(
+        "vault:v1:8SDd3WHDOjf7mq69CyCqYjBXAiQQAVZRkFM13ok481zoCmHnSeDX9vyf7w=="
+    ));
+    assert!(is_transit_ciphertext("vault:v12:abcd"));
+    assert!(!is_transit_ciphertext("vault:vx:abcd"));
+    assert!(!is_transit_ciphertext("password"));
+
+    let mut vault_key = VaultKey {
+        pubkey: PUBKEY.to_owned(),
+        password: Some("vault:v1:cGFzc3dvcmQ=".to_owned()),
+        raw_unencrypted_key: Some("vault:v1:cmF3".to_owned()),
+        ..Default::default()
+    };
+    assert_eq!(vault_key.transit_ciphertexts_mut(false).len(), 1);
+    for field in vault_key.transit_ciphertexts_mut(true) {
+        *field = "decrypted".to_owned();
+    }
+    assert_eq!(vault_key.password, Some("decrypted".to_owned()));
+    assert_eq!(vault_key.raw_unencrypted_key, Some("decrypted".to_owned()));
+
+    let mut vault_key = VaultKey {
+        pubkey: PUBKEY.to_owned(),
+        password: Some("password".to_owned()),
+        ..Default::default()
+    };
+    assert!(vault_key.transit_ciphertexts_mut(true).is_empty());
+}
+
+#[test]
+fn test_verify_pubkey() {
+    let vault_key = VaultKey {
+        pubkey: "0x9612d7a727c9d0a22e185a1c768478dfe919cada9266988cb32359c11f2b7b27f4ae4040902382ae2910c15e2b420d07".to_owned(),
+        raw_unencrypted_key: Some(
+            "0x000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f".to_owned(),
+        ),
+        ..Default::default()
+    };
+    assert!(vault_key.verify_pubkey(false).is_ok());
+
+    let vault_key = VaultKey {
+        pubkey: PUBKEY.to_owned(),
+        ..vault_key
+    };
+    let error = vault_key.verify_pubkey(false).unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+
+    let vault_key = VaultKey {
+        pubkey: PUBKEY.to_owned(),
+        password: Some("password".to_owned()),
+        vkey: Some(r#"{"crypto": {"kdf": {"function": "pbkdf2", "params": {"dklen": 32, "c": 2, "prf": "hmac-sha256", "salt": ""}, "message": ""}, "checksum": {"function": "sha256", "params": {}, "message": ""}, "cipher": {"function": "aes-128-ctr", "params": {"iv": ""}, "message": ""}}, "pubkey": "80034e0023d717adfb048eb867b6f2c01d079a917be06afb95471e3d82df25818103b30061c6f50a51d59653d902d0f8", "uuid": "", "version": 4}"#.to_owned()),
+        ..Default::default()
+    };
+    let error = vault_key.verify_pubkey(false).unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+
+    // Keystores that are not selected are not verified
+    let secret =
+        hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+    let keystore = Keystore::encrypt(&secret, "password", KdfFunction::Pbkdf2, 1).unwrap();
+    let vault_key = VaultKey {
+        pubkey: format!("0x{}", keystore.pubkey),
+        pbkdf2_key: Some(serde_json::to_string(&keystore).unwrap()),
+        ..vault_key
+    };
+    assert!(vault_key.verify_pubkey(true).is_ok());
+}
+
+#[test]
+fn test_validate_keystores() {
+    let vault_key = VaultKey {
+        pubkey: PUBKEY.to_owned(),
+        password: Some("password".to_owned()),
+        pbkdf2_key: Some("eyJjcnlwdG8iOnsiY2hlY2tzdW0iOnsiZnVuY3Rpb24iOiJzaGEyNTYiLCJtZXNzYWdlIjoiZTMyNjQ5OWNiODg3Mzg4NGIyZGJkODc3ZWRkOWNkOGZhODVjMjQ5ZWQ5N2YzYTBkN2FkMjQ2MTIxMzNmMzExOCIsInBhcmFtcyI6e319LCJjaXBoZXIiOnsiZnVuY3Rpb24iOiJhZXMtMTI4LWN0ciIsIm1lc3NhZ2UiOiJhNGI0OWJkNGVhOGRmNzU4NTJiNzMwMDI4MDI5MzA4MzU4NGJkN2EyZDhmMjAyMTdjNjU3NmIxODU3NGU1NzY5IiwicGFyYW1zIjp7Iml2IjoiYWZkYzM0M2Y4ODk0ODkyMDkyMWM3NzIxNGFlOGFhZmEifX0sImtkZiI6eyJmdW5jdGlvbiI6InBia2RmMiIsIm1lc3NhZ2UiOiIiLCJwYXJhbXMiOnsiYyI6MjYyMTQ0LCJka2xlbiI6MzIsInByZiI6ImhtYWMtc2hhMjU2Iiwic2FsdCI6IjVlODM2MDlkZmFmOGUxNDc2MDM0M2U5NTNkYjdjMWMxZTQ2ZmNmMTEwNGNlNjlhMDUwOTUwNDU5YzFhNzlmZTIifX19LCJkZXNjcmlwdGlvbiI6IiIsInB1YmtleSI6IjgwMDM0ZTAwMjNkNzE3YWRmYjA0OGViODY3YjZmMmMwMWQwNzlhOTE3YmUwNmFmYjk1NDcxZTNkODJkZjI1ODE4MTAzYjMwMDYxYzZmNTBhNTFkNTk2NTNkOTAyZDBmOCIsInBhdGgiOiJtLzEyMzgxLzM2MDAvMC8wLzAiLCJ1dWlkIjoiNWQyMDdlMmMtNDA4Mi00NTBjLTg1MGEtMjAwYzA0NWFiZjBmIiwidmVyc2lvbiI6NH0=".to_owned()),
+        ..Default::default()
+    };
+    assert!(vault_key.validate_keystores(false).is_ok());
+    // The test keystore is not encrypted with "password"
+    let error = vault_key.validate_keystores(true).unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+
+    // Only the selected keystore is validated
+    let mut vault_key = VaultKey {
+        vkey: Some(r#"{"crypto": {}, "version": 4}"#.to_owned()),
+        ..vault_key
+    };
+    assert!(vault_key.validate_keystores(false).is_ok());
+    vault_key.select_keystore(&[KeystoreVariant::Vkey]).unwrap();
+    let error = vault_key.validate_keystores(false).unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+}
+
+#[test]
+fn test_select_keystore() {
+    let mut vault_key = VaultKey {
+        pubkey: PUBKEY.to_owned(),
+        password: Some("password".to_owned()),
+        pbkdf2_key: Some("eyJjcnlwdG8iOnsiY2hlY2tzdW0iOnsiZnVuY3Rpb24iOiJzaGEyNTYiLCJtZXNzYWdlIjoiZTMyNjQ5OWNiODg3Mzg4NGIyZGJkODc3ZWRkOWNkOGZhODVjMjQ5ZWQ5N2YzYTBkN2FkMjQ2MTIxMzNmMzExOCIsInBhcmFtcyI6e319LCJjaXBoZXIiOnsiZnVuY3Rpb24iOiJhZXMtMTI4LWN0ciIsIm1lc3NhZ2UiOiJhNGI0OWJkNGVhOGRmNzU4NTJiNzMwMDI4MDI5MzA4MzU4NGJkN2EyZDhmMjAyMTdjNjU3NmIxODU3NGU1NzY5IiwicGFyYW1zIjp7Iml2IjoiYWZkYzM0M2Y4ODk0ODkyMDkyMWM3NzIxNGFlOGFhZmEifX0sImtkZiI6eyJmdW5jdGlvbiI6InBia2RmMiIsIm1lc3NhZ2UiOiIiLCJwYXJhbXMiOnsiYyI6MjYyMTQ0LCJka2xlbiI6MzIsInByZiI6ImhtYWMtc2hhMjU2Iiwic2FsdCI6IjVlODM2MDlkZmFmOGUxNDc2MDM0M2U5NTNkYjdjMWMxZTQ2ZmNmMTEwNGNlNjlhMDUwOTUwNDU5YzFhNzlmZTIifX19LCJkZXNjcmlwdGlvbiI6IiIsInB1YmtleSI6IjgwMDM0ZTAwMjNkNzE3YWRmYjA0OGViODY3YjZmMmMwMWQwNzlhOTE3YmUwNmFmYjk1NDcxZTNkODJkZjI1ODE4MTAzYjMwMDYxYzZmNTBhNTFkNTk2NTNkOTAyZDBmOCIsInBhdGgiOiJtLzEyMzgxLzM2MDAvMC8wLzAiLCJ1dWlkIjoiNWQyMDdlMmMtNDA4Mi00NTBjLTg1MGEtMjAwYzA0NWFiZjBmIiwidmVyc2lvbiI6NH0=".to_owned()),
+        vkey: Some("eyJjcnlwdG8iOiB7ImtkZiI6IHsiZnVuY3Rpb24iOiAic2NyeXB0IiwgInBhcmFtcyI6IHsiZGtsZW4iOiAzMiwgIm4iOiAyNjIxNDQsICJyIjogOCwgInAiOiAxLCAic2FsdCI6ICJmMTlhYmYxMWM0ODNmMWY2MDgwZGZlNjU4OTkxNDEyZTRhOGM3M2U1OTM4YmMzZWE3NDViYzdkMTJhNmJjZDlhIn0sICJtZXNzYWdlIjogIiJ9LCAiY2hlY2tzdW0iOiB7ImZ1bmN0aW9uIjogInNoYTI1NiIsICJwYXJhbXMiOiB7fSwgIm1lc3NhZ2UiOiAiYzc4Yzg5MjViNTNkYTBlYjcwMDY3ODhmZWEzMmY3NzMwYTM0YzllOTI2NTI2N2UzZmIxMjJiYTQyYTFiNjFlZiJ9LCAiY2lwaGVyIjogeyJmdW5jdGlvbiI6ICJhZXMtMTI4LWN0ciIsICJwYXJhbXMiOiB7Iml2IjogIjJhY2M1MDQ5OTc4YTQyYTAxMjE0ZDFhODdjMjBiNTRkIn0sICJtZXNzYWdlIjogIjUzNGVkOTgwNDkxMWM4MGFkMTUxOTg1NWQ4Mjg3MGMwZDYwZTFmZTViMDE3YzZhZTE2ZDI1ZjY5ZjhmODU2MTMifX0sICJkZXNjcmlwdGlvbiI6ICIiLCAicHVia2V5IjogIjgwMDM0ZTAwMjNkNzE3YWRmYjA0OGViODY3YjZmMmMwMWQwNzlhOTE3YmUwNmFmYjk1NDcxZTNkODJkZjI1ODE4MTAzYjMwMDYxYzZmNTBhNTFkNTk2NTNkOTAyZDBmOCIsICJwYXRoIjogIm0vMTIzODEvMzYwMC8wLzAvMCIsICJ1dWlkIjogIjVkMjA3ZTJjLTQwODItNDUwYy04NTBhLTIwMGMwNDVhYmYwZiIsICJ2ZXJzaW9uIjogNH0=".to_owned()),
+        ..Default::default()
+    };
+    let variant = vault_key
+        .select_keystore(&[KeystoreVariant::Vkey, KeystoreVariant::Pbkdf2])
+        .unwrap();
+    assert_eq!(variant, Some(KeystoreVariant::Vkey));
+    match vault_key.to_config().unwrap() {
+        Web3signerKeyConfigFormat::Web3signerFileKeystore(config) => {
+            assert_eq!(
+                config.keystore_file_content["crypto"]["kdf"]["function"],
+                "scrypt"
+            );
+        }
+        Web3signerKeyConfigFormat::Web3signerFileRaw(_) => panic!("expected a keystore"),
+    }
+
+    let variant = vault_key
+        .select_keystore(&KeystoreVariant::default_preference())
+        .unwrap();
+    assert_eq!(variant, Some(KeystoreVariant::Pbkdf2));
+
+    let error = vault_key
+        .select_keystore(&[KeystoreVariant::Scrypt])
+        .unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+
+    let mut vault_key = VaultKey {
+        raw_unencrypted_key: Some(
+            "0x800a5c977cb95148f71cd731bbfb44633fc3427975686b458d3670bc61150147".to_owned(),
+        ),
+        ..vault_key
+    };
+    let variant = vault_key
+        .select_keystore(&[KeystoreVariant::Scrypt])
+        .unwrap();
+    assert_eq!(variant, None);
+}
+
+#[test]
+fn test_reencrypt_keystore() {
+    let secret =
+        hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+    let keystore = Keystore::encrypt(&secret, "vault password", KdfFunction::Scrypt, 16).unwrap();
+    let mut vault_key = VaultKey {
+        pubkey: "0x9612d7a727c9d0a22e185a1c768478dfe919cada9266988cb32359c11f2b7b27f4ae4040902382ae2910c15e2b420d07".to_owned(),
+        password: Some("vault password".to_owned()),
+        scrypt_key: Some(serde_json::to_string(&keystore).unwrap()),
+        ..Default::default()
+    };
+    vault_key
+        .reencrypt_keystore("local password", KdfFunction::Pbkdf2, 16)
+        .unwrap();
+    assert_eq!(vault_key.password, Some("local password".to_owned()));
+
+    assert_eq!(vault_key.scrypt_key, None);
+    assert_eq!(vault_key.keystore_variant, Some(KeystoreVariant::Pbkdf2));
+    let keystore = parse_keystore(vault_key.pbkdf2_key.as_ref().unwrap()).unwrap();
+    assert_eq!(keystore.crypto.kdf.function, "pbkdf2");
+    assert_eq!(*keystore.decrypt("local password").unwrap(), secret);
+    assert!(vault_key.verify_pubkey(true).is_ok());
+
+    assert!(vault_key
+        .reencrypt_keystore("another password", KdfFunction::Pbkdf2, 16)
+        .is_ok());
+    vault_key.password = Some("wrong password".to_owned());
+    assert!(vault_key
+        .reencrypt_keystore("local password", KdfFunction::Pbkdf2, 16)
+        .is_err());
+}
+
+#[test]
+fn test_encrypt_raw_key() {
+    let mut vault_key = VaultKey {
+        pubkey: "0x9612d7a727c9d0a22e185a1c768478dfe919cada9266988cb32359c11f2b7b27f4ae4040902382ae2910c15e2b420d07".to_owned(),
+        raw_unencrypted_key: Some(
+            "0x000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f".to_owned(),
+        ),
+        ..Default::default()
+    };
+    vault_key
+        .encrypt_raw_key("local password", KdfFunction::Scrypt, 16)
+        .unwrap();
+    assert_eq!(vault_key.raw_unencrypted_key, None);
+    assert_eq!(vault_key.keystore_variant, Some(KeystoreVariant::Scrypt));
+    assert!(vault_key.verify_pubkey(true).is_ok());
+
+    match vault_key.to_config().unwrap() {
+        Web3signerKeyConfigFormat::Web3signerFileKeystore(config) => {
+            assert_eq!(
+                config.keystore_file_content["crypto"]["kdf"]["function"],
+                "scrypt"
+            );
+            assert_eq!(config.keystore_password_file_content, "local password");
+            assert!(!config.to_yaml().unwrap().contains("privateKey"));
+        }
+        Web3signerKeyConfigFormat::Web3signerFileRaw(_) => panic!("expected a keystore"),
+    }
+}
+
+#[test]
+fn test_keep_stored_keystore() {
+    let secret =
+        hex::decode("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f").unwrap();
+    let stored_keystore = Keystore::encrypt(&secret, "stored", KdfFunction::Pbkdf2, 16).unwrap();
+    let vault_key = VaultKey {
+        pubkey: "0x9612d7a727c9d0a22e185a1c768478dfe919cada9266988cb32359c11f2b7b27f4ae4040902382ae2910c15e2b420d07".to_owned(),
+        raw_unencrypted_key: Some(
+            "0x000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f".to_owned(),
+        ),
+        ..Default::default()
+    };
+
+    for (password, kdf, cost) in [
+        (None, KdfFunction::Pbkdf2, 32),
+        (None, KdfFunction::Scrypt, 16),
+    ] {
+        let mut vault_key = vault_key.clone();
+        assert!(!vault_key
+            .keep_stored_keystore(&stored_keystore, "stored", password, kdf, cost)
+            .unwrap());
+        assert!(vault_key.raw_unencrypted_key.is_some());
+    }
+    let mut other_vault_key = VaultKey {
+        raw_unencrypted_key: Some(
+            "0x800a5c977cb95148f71cd731bbfb44633fc3427975686b458d3670bc61150147".to_owned(),
+        ),
+        ..vault_key.clone()
+    };
+    assert!(!other_vault_key
+        .keep_stored_keystore(&stored_keystore, "stored", None, KdfFunction::Pbkdf2, 16)
+        .unwrap());
+
+    // A rotated password file only rewrites re-encrypted keystores
+    let mut keystore_vault_key = VaultKey {
+        raw_unencrypted_key: None,
+        password: Some("stored".to_owned()),
+        pbkdf2_key: Some(serde_json::to_string(&stored_keystore).unwrap()),
+        ..vault_key.clone()
+    };
+    keystore_vault_key
+        .select_keystore(&[KeystoreVariant::Pbkdf2])
+        .unwrap();
+    assert!(!keystore_vault_key
+        .keep_stored_keystore(
+            &stored_keystore,
+            "stored",
+            Some("rotated"),
+            KdfFunction::Pbkdf2,
+            16
+        )
+        .unwrap());
+
+    let mut vault_key = vault_key;
+    assert!(vault_key
+        .keep_stored_keystore(
+            &stored_keystore,
+            "stored",
+            Some("stored"),
+            KdfFunction::Pbkdf2,
+            16
+        )
+        .unwrap());
+    assert_eq!(vault_key.raw_unencrypted_key, None);
+    assert_eq!(vault_key.password, Some("stored".to_owned()));
+    assert_eq!(
+        parse_keystore(vault_key.pbkdf2_key.as_ref().unwrap()).unwrap(),
+        stored_keystore
+    );
+}
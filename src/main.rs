@@ -1,34 +1,63 @@
 use anyhow::{anyhow, Context, Error, Result};
 use clap::Parser;
 use futures::future::join_all;
-use keystores::Web3signerKeyConfig;
+use glob::glob;
 use log::{error, info, warn};
-use reqwest::{
-    header::{HeaderMap, HeaderName, HeaderValue},
-    Certificate, Client, ClientBuilder, Identity, Url,
-};
-use serde_json::Value;
+use reqwest::Url;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 use std::{fs, path::Path};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::sync::Semaphore;
 use tokio::time::sleep;
+use zeroize::Zeroizing;
 
+mod auth;
 mod cli;
 mod config;
+mod eip2335;
+mod key_store;
+mod keymanager;
 mod keystores;
+mod metrics;
+mod vault;
+mod web3signer;
 
 use crate::cli::Cli;
 use crate::config::Config;
-use crate::keystores::{VaultKey, Web3signerKeyConfigFormat};
+use crate::eip2335::{generate_password, KdfFunction};
+use crate::key_store::{
+    diff_vault_key, prepare_key_store, prune_keys, read_stored_keystore, stale_pubkeys,
+    write_vault_key, FilePermissions, KeyChange,
+};
+use crate::keymanager::{
+    build_keymanager_client, build_remotekeys_clients, parse_slashing_protection, ImportStatus,
+    KeymanagerClient, OutputBackend,
+};
+use crate::keystores::{RejectedVaultKey, VaultKey};
+use crate::metrics::{spawn_metrics_server, write_metrics_textfile, METRICS};
+use crate::vault::{
+    build_vault_client, get_vault_key, KvMount, KvVersion, SecretVersion, VaultClient,
+};
+use crate::web3signer::{build_web3signer_client, Web3signerClient};
 
-use glob::glob;
+/// Outcome of fetching the key of a public key from Vault
+type KeyResponse = Result<(String, VaultKey), (String, Error)>;
+
+/// Clients of the services that keys are delivered to
+struct Outputs {
+    web3signer_client: Option<Web3signerClient>,
+    keymanager_client: Option<KeymanagerClient>,
+    remotekeys_clients: Vec<KeymanagerClient>,
+}
 
 fn parse_public_keys(config: &Config) -> Result<Vec<String>> {
-    let pattern: &str = config.vault_pubkeys_json_glob.as_ref();
+    let pattern: &str = config
+        .vault_pubkeys_json_glob
+        .as_deref()
+        .ok_or_else(|| anyhow!("vault_pubkeys_json_glob must be set"))?;
     let mut pubkeys: Vec<String> = Vec::new();
 
     match glob(pattern) {
@@ -53,138 +82,283 @@ fn parse_public_keys(config: &Config) -> Result<Vec<String>> {
     Ok(pubkeys)
 }
 
-fn parse_configuration(args: &Cli) -> Result<Config> {
-    match Config::new(args) {
-        Ok(config) => Ok(config),
+async fn discover_public_keys(
+    config: &Config,
+    vault_client: &VaultClient,
+    kv_mount: &KvMount,
+) -> Result<BTreeMap<String, String>> {
+    let mut pubkeys = match vault_client.list_public_keys(kv_mount).await {
+        Ok(pubkeys) => pubkeys,
         Err(error) => {
-            error!("Failed to parse configuration: {}", error);
-            Err(error).context("Failed to parse configuration")
+            error!("Failed to list public keys in Vault: {}", error);
+            return Err(error).context("Failed to list public keys in Vault");
+        }
+    };
+
+    if config.vault_pubkeys_json_glob.is_some() {
+        info!("Restricting discovered public keys to the public keys file");
+        let allowed_pubkeys: HashSet<String> = parse_public_keys(config)?.into_iter().collect();
+        for pubkey in &allowed_pubkeys {
+            if !pubkeys.contains_key(pubkey) {
+                warn!("Public key {} is not stored in Vault", pubkey);
+            }
         }
+        pubkeys.retain(|pubkey, _| allowed_pubkeys.contains(pubkey));
     }
+    Ok(pubkeys)
 }
 
-fn build_vault_client(config: &Config) -> Result<Client> {
-    info!("Reading vault token from file",);
-    let vault_token = parse_token(config)?;
-    info!("Vault token read successfully");
+fn parse_secret_versions(config: &Config) -> Result<HashMap<String, SecretVersion>> {
+    match &config.vault_versions_json_path {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(versions) => Ok(serde_json::from_str(&versions)?),
+            Err(error) => {
+                error!("Failed to read secret versions file: {}", error);
+                Err(error).context("Failed to read secret versions file")
+            }
+        },
+        None => Ok(HashMap::new()),
+    }
+}
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        HeaderName::from_static("x-vault-token"),
-        HeaderValue::from_str(&vault_token)?,
-    );
+fn write_secret_versions<'a>(
+    path: &Path,
+    vault_keys: impl Iterator<Item = &'a VaultKey>,
+) -> Result<()> {
+    let versions: BTreeMap<&String, &SecretVersion> = vault_keys
+        .filter_map(|vault_key| {
+            vault_key
+                .version
+                .as_ref()
+                .map(|version| (&vault_key.pubkey, version))
+        })
+        .collect();
+    match fs::write(path, serde_json::to_string_pretty(&versions)?) {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            error!("Failed to write secret versions report: {}", error);
+            Err(error).context("Failed to write secret versions report")
+        }
+    }
+}
 
-    info!("Checking TLS configuration");
-    let vault_cacert = config.vault_cacert.as_ref().and_then(|vault_cacert| {
-        if let Ok(vault_cacert) = fs::read(vault_cacert) {
-            info!("CA certificate provided, TLS authentication enabled");
-            Some(Certificate::from_pem(&vault_cacert).ok()).flatten()
-        } else {
-            info!("CA certificate not provided, TLS authentication disabled");
-            None
+/// Writes the keys that changed to the key store, returning their public keys
+async fn write_keys(config: &Config, responses: Vec<KeyResponse>) -> Result<BTreeSet<String>> {
+    let semaphore = Arc::new(Semaphore::new(config.max_open_file_descriptors));
+    let mut tasks = vec![];
+
+    for response in responses {
+        match response {
+            Ok((pubkey, vault_key)) => {
+                info!("Writing private key for {}", pubkey);
+                let permit = semaphore.clone().acquire_owned().await?;
+                let web3signer_key_store_path = config.web3signer_key_store_path.clone();
+                let permissions = FilePermissions::new(config);
+                let pubkey_clone = pubkey.clone();
+                let task = tokio::spawn(async move {
+                    let sleep_duration_seconds = Duration::from_secs(1);
+                    if let Ok(KeyChange::Unchanged) =
+                        diff_vault_key(&vault_key, &web3signer_key_store_path, &permissions).await
+                    {
+                        drop(permit);
+                        return false;
+                    }
+                    loop {
+                        match write_vault_key(&vault_key, &web3signer_key_store_path, &permissions)
+                            .await
+                        {
+                            Ok(_) => {
+                                drop(permit);
+                                break true;
+                            }
+                            Err(e) => {
+                                error!(
+                                    "Failed to write private key for {}: {}, retrying in {}s",
+                                    pubkey_clone,
+                                    e,
+                                    sleep_duration_seconds.as_secs()
+                                );
+                                sleep(sleep_duration_seconds).await;
+                            }
+                        }
+                    }
+                });
+                tasks.push((pubkey, task));
+            }
+            Err((pubkey, e)) => {
+                error!("Failed to write private key for {}: {}", pubkey, e);
+            }
         }
-    });
-
-    let vault_client_auth = if let (Some(vault_client_cert), Some(vault_client_key)) =
-        (&config.vault_client_cert, &config.vault_client_key)
-    {
-        if let (Ok(vault_client_cert), Ok(vault_client_key)) = (
-            fs::read_to_string(vault_client_cert),
-            fs::read_to_string(vault_client_key),
-        ) {
-            info!("Client certificate and key provided, TLS authentication enabled");
-            Some(Identity::from_pem(
-                (vault_client_cert + &vault_client_key).as_bytes(),
-            )?)
-        } else {
-            info!("Client certificate and key not provided, TLS authentication disabled");
-            None
+    }
+
+    let writes: Vec<_> = join_all(tasks.into_iter().map(|(pubkey, task)| async move {
+        match task.await {
+            Ok(true) => {
+                info!("Private key written successfully for: {}", pubkey);
+                Some(pubkey)
+            }
+            Ok(false) => {
+                info!("Private key unchanged for: {}", pubkey);
+                None
+            }
+            Err(e) => {
+                error!("Failed to write private key for {}: {}", pubkey, e);
+                None
+            }
         }
-    } else {
-        None
-    };
+    }))
+    .await;
+    Ok(writes.into_iter().flatten().collect())
+}
 
-    match if let (Some(vault_client_auth), Some(vault_cacert)) = (vault_client_auth, vault_cacert) {
-        info!("Building Vault client with TLS authentication");
-        ClientBuilder::new()
-            .add_root_certificate(vault_cacert)
-            .identity(vault_client_auth)
-            .default_headers(headers)
-            .use_rustls_tls()
-            .build()
-    } else {
-        info!("Building Vault client without TLS authentication");
-        ClientBuilder::new().default_headers(headers).build()
-    } {
-        Ok(vault_client) => Ok(vault_client),
-        Err(error) => {
-            error!("Failed to create vault client: {}", error);
-            Err(error).context("Failed to create vault client")
+/// Imports the keystores through the Keymanager API, returning the public keys
+/// that were imported and the number of keys that failed to import
+async fn import_keys(
+    config: &Config,
+    keymanager_client: &KeymanagerClient,
+    responses: Vec<KeyResponse>,
+) -> Result<(BTreeSet<String>, usize)> {
+    let slashing_protection = parse_slashing_protection(config)?;
+    let vault_keys: Vec<VaultKey> = responses
+        .into_iter()
+        .flatten()
+        .map(|(_, vault_key)| vault_key)
+        .collect();
+    let statuses = keymanager_client
+        .import_vault_keys(
+            &vault_keys.iter().collect::<Vec<_>>(),
+            config.keymanager_batch_size,
+            slashing_protection.as_deref(),
+        )
+        .await;
+
+    let mut imported_pubkeys = BTreeSet::new();
+    let mut failed_imports = 0;
+    for (pubkey, status) in statuses {
+        match status {
+            Ok(ImportStatus::Imported) => {
+                info!("Private key imported successfully for: {}", pubkey);
+                imported_pubkeys.insert(pubkey);
+            }
+            Ok(_) => info!("Private key already imported for: {}", pubkey),
+            Err(e) => {
+                error!("Failed to import private key for {}: {}", pubkey, e);
+                failed_imports += 1;
+            }
         }
     }
+    Ok((imported_pubkeys, failed_imports))
 }
 
-fn parse_token(config: &Config) -> Result<String> {
-    match config.vault_token_path.canonicalize() {
-        Ok(vault_token_path) => {
-            info!(
-                "Reading vault token from file: {}",
-                vault_token_path.display()
-            );
-        }
-        Err(error) => {
-            error!("Failed to canonicalize vault token file path: {}", error);
-            return Err(error).context("Failed to canonicalize vault token file path");
+/// Prints how each key would change in the key store, without its secrets,
+/// and returns the number of keys that would be added, changed or removed.
+/// Keys encrypted locally are compared once the keystores that still hold
+/// their secret have been kept, so they only change with the secret.
+async fn diff_key_store<'a>(
+    config: &Config,
+    vault_keys: impl Iterator<Item = &'a VaultKey>,
+    requested_pubkeys: &BTreeSet<String>,
+) -> Result<usize> {
+    let path = &config.web3signer_key_store_path;
+    let permissions = FilePermissions::new(config);
+    let mut changes: BTreeMap<String, KeyChange> = BTreeMap::new();
+    for vault_key in vault_keys {
+        changes.insert(
+            vault_key.pubkey.clone(),
+            diff_vault_key(vault_key, path, &permissions).await?,
+        );
+    }
+    if config.prune || config.prune_dry_run {
+        for pubkey in stale_pubkeys(path, requested_pubkeys)? {
+            changes.insert(pubkey, KeyChange::Removed);
         }
     }
 
-    match fs::read_to_string(&config.vault_token_path) {
-        Ok(token) => Ok(token),
-        Err(error) => {
-            error!("Failed to read vault token: {}", error);
-            Err(error).context("Failed to read vault token from file")
-        }
+    for (pubkey, change) in &changes {
+        println!("{} {}", change, pubkey);
+    }
+    let count = |kind: KeyChange| changes.values().filter(|change| **change == kind).count();
+    println!(
+        "{} added, {} changed, {} unchanged, {} removed",
+        count(KeyChange::Added),
+        count(KeyChange::Changed),
+        count(KeyChange::Unchanged),
+        count(KeyChange::Removed)
+    );
+    Ok(changes
+        .values()
+        .filter(|change| **change != KeyChange::Unchanged)
+        .count())
+}
+
+/// Reads the password of re-encrypted keystores, `None` meaning that one is
+/// generated for each key
+fn parse_reencrypt_password(config: &Config) -> Result<Option<Zeroizing<String>>> {
+    match &config.reencrypt_password_path {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(password) => {
+                let password = Zeroizing::new(password);
+                if password.trim().is_empty() {
+                    return Err(anyhow!(
+                        "Keystore password file {} is empty",
+                        path.display()
+                    ));
+                }
+                Ok(Some(Zeroizing::new(password.trim().to_string())))
+            }
+            Err(error) => {
+                error!("Failed to read keystore password file: {}", error);
+                Err(error).context("Failed to read keystore password file")
+            }
+        },
+        None => Ok(None),
     }
 }
 
-async fn get_vault_key(vault_client: &Client, url: Url, pubkey: &str) -> Result<VaultKey, Error> {
-    VaultKey::new(
-        vault_client.get(url).send().await?.json::<Value>().await?["data"]["data"].clone(),
-        pubkey,
-    )
+/// Cost parameter of `keystore_kdf`
+fn keystore_kdf_cost(config: &Config) -> u32 {
+    match config.keystore_kdf {
+        KdfFunction::Pbkdf2 => config.keystore_pbkdf2_c,
+        KdfFunction::Scrypt => config.keystore_scrypt_n,
+    }
 }
 
-async fn write_vault_key(vault_key: &VaultKey, path: &Path) -> Result<(), Error> {
-    match vault_key.to_config()? {
-        Web3signerKeyConfigFormat::Web3signerFileRaw(config) => {
-            let path_config = path.join(&config.filename);
-            let mut file_config = File::create(path_config).await?;
-            file_config.write_all(config.to_yaml()?.as_bytes()).await?;
-            Ok(())
+/// Writes which keystore, or `raw` for a raw private key, is written for
+/// each public key
+fn write_keystore_report<'a>(
+    path: &Path,
+    vault_keys: impl Iterator<Item = &'a VaultKey>,
+) -> Result<()> {
+    let keystores: BTreeMap<&String, String> = vault_keys
+        .map(|vault_key| {
+            let keystore = match vault_key.keystore_variant {
+                Some(variant) => variant.to_string(),
+                None => "raw".to_string(),
+            };
+            (&vault_key.pubkey, keystore)
+        })
+        .collect();
+    match fs::write(path, serde_json::to_string_pretty(&keystores)?) {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            error!("Failed to write keystore report: {}", error);
+            Err(error).context("Failed to write keystore report")
         }
-        Web3signerKeyConfigFormat::Web3signerFileKeystore(config) => {
-            let path_config = path.join(&config.filename);
-            let path_keystore = path.join(&config.keystore_file);
-            let path_password = path.join(&config.keystore_password_file);
-            let mut file_config = File::create(path_config).await?;
-            let mut file_keystore = File::create(path_keystore).await?;
-            let mut file_password = File::create(path_password).await?;
-            file_config.write_all(config.to_yaml()?.as_bytes()).await?;
-            file_keystore
-                .write_all(serde_json::to_vec(&config.keystore_file_content)?.as_ref())
-                .await?;
-            file_password
-                .write_all(config.keystore_password_file_content.as_bytes())
-                .await?;
-            Ok(())
+    }
+}
+
+fn parse_configuration(args: &Cli) -> Result<Config> {
+    match Config::new(args) {
+        Ok(config) => Ok(config),
+        Err(error) => {
+            error!("Failed to parse configuration: {}", error);
+            Err(error).context("Failed to parse configuration")
         }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let start = Instant::now();
-
     let args = Cli::parse();
 
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
@@ -195,53 +369,162 @@ async fn main() -> Result<()> {
     let config = parse_configuration(&args)?;
     info!("Configuration parsed successfully");
 
-    info!("Reading public keys from file");
-    let pubkeys = parse_public_keys(&config)?;
-    info!("Public keys read from file successfully");
+    // Registered before logging in, so that a SIGHUP sent while the daemon
+    // starts up does not kill it
+    let hangup = if config.daemon {
+        Some(signal(SignalKind::hangup())?)
+    } else {
+        None
+    };
+
+    if config.output_backend == OutputBackend::Files {
+        prepare_key_store(&config)?;
+    }
 
     info!("Building vault client");
-    let vault_client = build_vault_client(&config)?;
+    let vault_client = build_vault_client(&config).await?;
     info!("Vault client built successfully");
 
+    info!("Detecting KV engine version of {}", config.vault_path);
+    let kv_mount = vault_client.kv_mount(&config.vault_path).await?;
+    info!(
+        "Reading secrets from KV {:?} mount {} at {}",
+        kv_mount.version, kv_mount.mount, kv_mount.data_path
+    );
+
+    let outputs = Outputs {
+        web3signer_client: build_web3signer_client(&config)?,
+        keymanager_client: build_keymanager_client(&config)?,
+        remotekeys_clients: build_remotekeys_clients(&config)?,
+    };
+
+    if let Some(hangup) = hangup {
+        if let Some(address) = config.metrics_listen_address {
+            spawn_metrics_server(address)?;
+        }
+        return run_daemon(&config, &vault_client, &kv_mount, &outputs, hangup).await;
+    }
+    let result = load_keys(&config, &vault_client, &kv_mount, &outputs).await;
+    // A dry run writes nothing, the metrics textfile included
+    if let Some(path) = &config.metrics_textfile_path {
+        if !config.dry_run {
+            write_metrics_textfile(path).await?;
+        }
+    }
+    result?;
+    Ok(())
+}
+
+/// Loads keys again every `daemon_interval` seconds and on SIGHUP, until the
+/// Vault token can no longer be renewed
+async fn run_daemon(
+    config: &Config,
+    vault_client: &VaultClient,
+    kv_mount: &KvMount,
+    outputs: &Outputs,
+    mut hangup: Signal,
+) -> Result<()> {
+    let interval = Duration::from_secs(config.daemon_interval);
+    loop {
+        match load_keys(config, vault_client, kv_mount, outputs).await {
+            Ok(changes) => info!("{} keys changed in the key store", changes),
+            Err(e) => error!("Failed to load keys: {}", e),
+        }
+        if let Some(path) = &config.metrics_textfile_path {
+            if let Err(e) = write_metrics_textfile(path).await {
+                error!("Failed to write metrics: {}", e);
+            }
+        }
+        if let Some(token_error) = vault_client.token_error() {
+            return Err(anyhow!(token_error));
+        }
+
+        tokio::select! {
+            _ = sleep(interval), if config.daemon_interval > 0 => {
+                info!("Loading keys again after {}s", config.daemon_interval);
+            }
+            _ = hangup.recv() => info!("Received SIGHUP, loading keys again"),
+        }
+    }
+}
+
+/// Fetches the keys of the public keys, each mapped to its sub-folder, from
+/// Vault and decrypts the ones wrapped with Transit
+async fn fetch_keys(
+    config: &Config,
+    vault_client: &VaultClient,
+    kv_mount: &KvMount,
+    versions: &HashMap<String, SecretVersion>,
+    pubkeys: BTreeMap<String, String>,
+) -> Result<Vec<KeyResponse>> {
     let semaphore = Arc::new(Semaphore::new(config.vault_max_concurrent_requests));
     let mut tasks = vec![];
 
-    for pubkey in pubkeys {
+    for (pubkey, folder) in pubkeys {
         info!("Requesting private key for {}", pubkey);
         let vault_client = vault_client.clone();
+        let kv_mount = kv_mount.clone();
         let permit = semaphore.clone().acquire_owned().await?;
-        let url = Url::parse(&format!(
-            "{}/v1/{}/{}/vkey",
-            &config.vault_addr, &config.vault_path, pubkey,
+        let mut url = Url::parse(&format!(
+            "{}/v1/{}",
+            &config.vault_addr,
+            kv_mount.secret_path(&config.vault_secret_path_template, &folder, &pubkey),
         ))?;
+        if let Some(version) = versions.get(&pubkey) {
+            info!("Requesting version {} of {}", version.version, pubkey);
+            url.query_pairs_mut()
+                .append_pair("version", &version.version.to_string());
+        }
         let pubkey_clone = pubkey.clone();
         let task = tokio::spawn(async move {
             let sleep_duration_seconds = Duration::from_secs(1);
             loop {
-                if let Ok(vault_key) =
-                    get_vault_key(&vault_client, url.clone(), &pubkey_clone).await
-                {
-                    drop(permit);
-                    break vault_key;
-                } else {
-                    warn!(
-                        "Failed to retrieve private key for {}, retrying in {}s...",
-                        pubkey_clone,
-                        sleep_duration_seconds.as_secs()
-                    );
-                    sleep(Duration::from_secs(1)).await;
+                match get_vault_key(&vault_client, &kv_mount, url.clone(), &pubkey_clone).await {
+                    Ok(vault_key) => {
+                        drop(permit);
+                        break Ok(vault_key);
+                    }
+                    Err(error) if error.is::<RejectedVaultKey>() => {
+                        drop(permit);
+                        break Err(error);
+                    }
+                    Err(_) => {
+                        if let Some(token_error) = vault_client.token_error() {
+                            drop(permit);
+                            break Err(anyhow!(token_error));
+                        }
+                        METRICS.vault_retries.inc();
+                        warn!(
+                            "Failed to retrieve private key for {}, retrying in {}s...",
+                            pubkey_clone,
+                            sleep_duration_seconds.as_secs()
+                        );
+                        sleep(Duration::from_secs(1)).await;
+                    }
                 }
             }
         });
         tasks.push((pubkey, task));
     }
 
-    let responses: Vec<_> = join_all(tasks.into_iter().map(|(pubkey, task)| async move {
+    let mut responses: Vec<_> = join_all(tasks.into_iter().map(|(pubkey, task)| async move {
         match task.await {
-            Ok(vault_key) => {
-                info!("Received private key for: {}", pubkey);
+            Ok(Ok(vault_key)) => {
+                match &vault_key.version {
+                    Some(version) => info!(
+                        "Received private key for: {} (version {} created at {})",
+                        pubkey,
+                        version.version,
+                        version.created_time.as_deref().unwrap_or("unknown")
+                    ),
+                    None => info!("Received private key for: {}", pubkey),
+                }
                 Ok((pubkey, vault_key))
             }
+            Ok(Err(e)) => {
+                error!("Failed to retrieve private key for {}: {}", pubkey, e);
+                Err((pubkey, e))
+            }
             Err(e) => {
                 error!("Failed to retrieve private key for {}: {:?}", pubkey, e);
                 Err((pubkey, anyhow!(e)))
@@ -250,60 +533,422 @@ async fn main() -> Result<()> {
     }))
     .await;
 
-    let semaphore = Arc::new(Semaphore::new(config.max_open_file_descriptors));
-    let mut tasks = vec![];
-
-    for response in responses {
-        match response {
-            Ok((pubkey, vault_key)) => {
-                info!("Writing private key for {}", pubkey);
-                let permit = semaphore.clone().acquire_owned().await?;
-                let web3signer_key_store_path = config.web3signer_key_store_path.clone();
-                let pubkey_clone = pubkey.clone();
-                let task = tokio::spawn(async move {
-                    let sleep_duration_seconds = Duration::from_secs(1);
-                    loop {
-                        match write_vault_key(&vault_key, &web3signer_key_store_path).await {
-                            Ok(_) => {
-                                drop(permit);
-                                break;
-                            }
-                            Err(e) => {
-                                error!(
-                                    "Failed to write private key for {}: {}, retrying in {}s",
-                                    pubkey_clone,
-                                    e,
-                                    sleep_duration_seconds.as_secs()
-                                );
-                            }
-                        }
-                    }
-                });
-                tasks.push((pubkey, task));
-            }
-            Err((pubkey, e)) => {
-                error!("Failed to write private key for {}: {}", pubkey, e);
+    let mut failed_decryptions = vault_client
+        .decrypt_transit_secrets(
+            responses
+                .iter_mut()
+                .flatten()
+                .map(|(_, vault_key)| vault_key)
+                .collect(),
+        )
+        .await;
+    for response in responses.iter_mut() {
+        if let Ok((pubkey, _)) = response {
+            if let Some(e) = failed_decryptions.remove(pubkey) {
+                error!("Failed to decrypt private key for {}: {}", pubkey, e);
+                *response = Err((pubkey.clone(), e));
             }
         }
     }
 
-    let _writes: Vec<_> = join_all(tasks.into_iter().map(|(pubkey, task)| async move {
-        match task.await {
-            Ok(_) => {
-                info!("Private key written successfully for: {}", pubkey);
-                Ok(())
+    Ok(responses)
+}
+
+/// Selects the keystore of each key and validates it, failing the keys that
+/// are invalid
+async fn verify_keys(config: &Config, responses: &mut [KeyResponse]) {
+    for response in responses.iter_mut() {
+        if let Ok((pubkey, vault_key)) = response {
+            match vault_key.select_keystore(&config.keystore_preference) {
+                Ok(Some(variant)) => info!("Using {} keystore for {}", variant, pubkey),
+                Ok(None) => info!("Using raw private key for {}", pubkey),
+                Err(e) => {
+                    error!("Failed to select a keystore for {}: {}", pubkey, e);
+                    *response = Err((pubkey.clone(), e));
+                }
             }
-            Err(e) => {
-                error!("Failed to write private key for {}: {}", pubkey, e);
-                Err(anyhow!(e))
+        }
+    }
+
+    info!("Validating private keys");
+    let semaphore = Arc::new(Semaphore::new(config.max_concurrent_kdf));
+    let verifications = join_all(responses.iter().flatten().map(|(_, vault_key)| {
+        let vault_key = vault_key.clone();
+        let semaphore = semaphore.clone();
+        let verify_keystore_passwords = config.verify_keystore_passwords;
+        let verify_pubkeys = config.verify_pubkeys;
+        let decrypt_keystore = config.verify_pubkeys_decrypt;
+        tokio::spawn(async move {
+            // KDFs are CPU-bound, they run on the blocking pool a few at a time
+            let _permit = semaphore.acquire_owned().await?;
+            tokio::task::spawn_blocking(move || {
+                vault_key.validate_keystores(verify_keystore_passwords)?;
+                if verify_pubkeys {
+                    vault_key.verify_pubkey(decrypt_keystore)?;
+                }
+                Ok::<_, Error>(())
+            })
+            .await?
+        })
+    }))
+    .await;
+    let mut verifications = verifications.into_iter();
+    for response in responses.iter_mut() {
+        if let Ok((pubkey, _)) = response {
+            let verification = match verifications.next() {
+                Some(Ok(verification)) => verification,
+                Some(Err(e)) => Err(anyhow!(e)),
+                None => Err(anyhow!("Private key was not validated")),
+            };
+            if let Err(e) = verification {
+                error!("Invalid private key for {}: {}", pubkey, e);
+                *response = Err((pubkey.clone(), e));
             }
         }
+    }
+}
+
+/// Encrypts raw keys and re-encrypts keystores with a local password, failing
+/// the keys that could not be encrypted
+async fn encrypt_keys(config: &Config, responses: &mut [KeyResponse]) -> Result<()> {
+    info!(
+        "Encrypting private keys with {} and a local password",
+        config.keystore_kdf
+    );
+    let semaphore = Arc::new(Semaphore::new(config.max_concurrent_kdf));
+    let password = if config.reencrypt_keystores {
+        parse_reencrypt_password(config)?
+    } else {
+        None
+    };
+    let kdf = config.keystore_kdf;
+    let cost = keystore_kdf_cost(config);
+    let reencrypt_keystores = config.reencrypt_keystores;
+    let encrypt_raw_keys = config.encrypt_raw_keys;
+    // Keystores already in the key store are kept when they still hold
+    // the secret, so that keys are not rewritten on every run
+    let key_store_path = match config.output_backend {
+        OutputBackend::Files => Some(config.web3signer_key_store_path.clone()),
+        OutputBackend::Keymanager => None,
+    };
+    let reencryptions = join_all(responses.iter().flatten().map(|(_, vault_key)| {
+        let mut vault_key = vault_key.clone();
+        let semaphore = semaphore.clone();
+        let password = password.clone();
+        let key_store_path = key_store_path.clone();
+        tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            tokio::task::spawn_blocking(move || {
+                let encrypt = if vault_key.raw_unencrypted_key.is_some() {
+                    encrypt_raw_keys
+                } else {
+                    reencrypt_keystores
+                };
+                if !encrypt {
+                    return Ok(vault_key);
+                }
+                let stored = match &key_store_path {
+                    Some(path) => read_stored_keystore(path, &vault_key.pubkey)?,
+                    None => None,
+                };
+                if let Some(stored) = stored {
+                    let password = password.as_deref().map(String::as_str);
+                    if vault_key.keep_stored_keystore(
+                        &stored.keystore,
+                        &stored.password,
+                        password,
+                        kdf,
+                        cost,
+                    )? {
+                        return Ok(vault_key);
+                    }
+                }
+                if vault_key.raw_unencrypted_key.is_some() {
+                    vault_key.encrypt_raw_key(&generate_password(), kdf, cost)?;
+                } else {
+                    let password = password.unwrap_or_else(generate_password);
+                    vault_key.reencrypt_keystore(&password, kdf, cost)?;
+                }
+                Ok::<_, Error>(vault_key)
+            })
+            .await?
+        })
     }))
     .await;
+    let mut reencryptions = reencryptions.into_iter();
+    for response in responses.iter_mut() {
+        if let Ok((pubkey, vault_key)) = response {
+            let reencryption = match reencryptions.next() {
+                Some(Ok(reencryption)) => reencryption,
+                Some(Err(e)) => Err(anyhow!(e)),
+                None => Err(anyhow!("Private key was not encrypted")),
+            };
+            match reencryption {
+                Ok(reencrypted_vault_key) => *vault_key = reencrypted_vault_key,
+                Err(e) => {
+                    error!("Failed to encrypt private key for {}: {}", pubkey, e);
+                    *response = Err((pubkey.clone(), e));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the keys that changed to the key store and prunes the stale ones,
+/// returning the public keys written and the number of keys changed
+async fn output_files(
+    config: &Config,
+    responses: Vec<KeyResponse>,
+    requested_pubkeys: &BTreeSet<String>,
+) -> Result<(BTreeSet<String>, usize)> {
+    let written_pubkeys = write_keys(config, responses).await?;
+    METRICS.keys_written.inc_by(written_pubkeys.len() as u64);
+    let mut changes = written_pubkeys.len();
+
+    if config.prune || config.prune_dry_run {
+        info!(
+            "Pruning stale keys from {}",
+            config.web3signer_key_store_path.display()
+        );
+        let pruned_pubkeys = prune_keys(
+            &config.web3signer_key_store_path,
+            requested_pubkeys,
+            config.prune_max_keys,
+            config.prune_dry_run,
+        )
+        .await?;
+        if config.prune_dry_run {
+            info!("{} stale keys would be pruned", pruned_pubkeys.len());
+        } else {
+            info!("{} stale keys pruned", pruned_pubkeys.len());
+            changes += pruned_pubkeys.len();
+        }
+    }
+    Ok((written_pubkeys, changes))
+}
+
+/// Imports the keys through the Keymanager API, returning the public keys
+/// imported and the number of keys that failed to import
+async fn output_keymanager(
+    config: &Config,
+    keymanager_client: &KeymanagerClient,
+    responses: Vec<KeyResponse>,
+) -> Result<(BTreeSet<String>, usize)> {
+    let (imported_pubkeys, failed_imports) =
+        import_keys(config, keymanager_client, responses).await?;
+    METRICS.keys_written.inc_by(imported_pubkeys.len() as u64);
+    Ok((imported_pubkeys, failed_imports))
+}
+
+/// Reloads web3signer and returns the written public keys it did not load
+async fn reload_web3signer(
+    config: &Config,
+    web3signer_client: &Web3signerClient,
+    written_pubkeys: &BTreeSet<String>,
+) -> Result<Vec<String>> {
+    web3signer_client.reload().await?;
+    let missing_pubkeys = web3signer_client
+        .wait_for_keys(
+            written_pubkeys,
+            Duration::from_secs(config.web3signer_reload_timeout),
+        )
+        .await?;
+    for pubkey in &missing_pubkeys {
+        error!("web3signer did not load {}", pubkey);
+    }
+    Ok(missing_pubkeys)
+}
+
+/// Registers the loaded keys as remote keys of each validator client,
+/// returning the number of validator clients that failed to sync
+async fn sync_remote_keys(
+    config: &Config,
+    outputs: &Outputs,
+    pubkeys: &BTreeSet<String>,
+    requested_pubkeys: &BTreeSet<String>,
+) -> Result<usize> {
+    let signer_url = config
+        .remotekeys_signer_url
+        .as_ref()
+        .or(config.web3signer_url.as_ref())
+        .ok_or_else(|| anyhow!("remotekeys_signer_url must be set"))?;
+    let mut failed_remotekeys = 0;
+    for remotekeys_client in &outputs.remotekeys_clients {
+        if let Err(e) = remotekeys_client
+            .sync_remote_keys(
+                pubkeys,
+                requested_pubkeys,
+                signer_url,
+                config.remotekeys_prune,
+            )
+            .await
+        {
+            error!("Failed to sync remote keys: {}", e);
+            failed_remotekeys += 1;
+        }
+    }
+    Ok(failed_remotekeys)
+}
+
+/// Fetches every requested key from Vault and writes the ones that changed
+/// to the key store, returning the number of keys written or pruned
+async fn load_keys(
+    config: &Config,
+    vault_client: &VaultClient,
+    kv_mount: &KvMount,
+    outputs: &Outputs,
+) -> Result<usize> {
+    let start = Instant::now();
+
+    let versions = parse_secret_versions(config)?;
+    if !versions.is_empty() {
+        info!("Pinning {} secrets to a version", versions.len());
+    }
+    if !versions.is_empty() && kv_mount.version != KvVersion::V2 {
+        return Err(anyhow!(
+            "Secret versions can only be pinned on a KV v2 mount"
+        ));
+    }
+
+    let pubkeys: BTreeMap<String, String> = if config.vault_pubkeys_discovery {
+        info!("Discovering public keys in Vault");
+        let pubkeys = discover_public_keys(config, vault_client, kv_mount).await?;
+        info!("{} public keys discovered in Vault", pubkeys.len());
+        pubkeys
+    } else {
+        info!("Reading public keys from file");
+        let pubkeys = parse_public_keys(config)?;
+        info!("Public keys read from file successfully");
+        pubkeys
+            .into_iter()
+            .map(|pubkey| (pubkey, String::new()))
+            .collect()
+    };
+
+    let requested_pubkeys: BTreeSet<String> = pubkeys.keys().cloned().collect();
+    METRICS
+        .keys_requested
+        .inc_by(requested_pubkeys.len() as u64);
+
+    let mut responses = fetch_keys(config, vault_client, kv_mount, &versions, pubkeys).await?;
+    verify_keys(config, &mut responses).await;
+    if config.reencrypt_keystores || config.encrypt_raw_keys {
+        encrypt_keys(config, &mut responses).await?;
+    }
+
+    let failed_responses = responses
+        .iter()
+        .filter(|response| response.is_err())
+        .count();
+    METRICS
+        .keys_fetched
+        .inc_by((responses.len() - failed_responses) as u64);
+    METRICS.keys_failed.inc_by(failed_responses as u64);
+
+    if config.dry_run {
+        let changes = diff_key_store(
+            config,
+            responses.iter().flatten().map(|(_, vault_key)| vault_key),
+            &requested_pubkeys,
+        )
+        .await?;
+        let elapsed = Instant::now() - start;
+        METRICS.load_duration.observe(elapsed.as_secs_f64());
+        println!("Elapsed time: {:.2?}", elapsed);
+        if failed_responses > 0 {
+            return Err(anyhow!(
+                "Failed to retrieve {} private keys from Vault",
+                failed_responses
+            ));
+        }
+        if changes > 0 {
+            return Err(anyhow!("{} keys would change", changes));
+        }
+        return Ok(0);
+    }
+
+    if let Some(path) = &config.vault_versions_report_path {
+        info!("Writing secret versions report to {}", path.display());
+        write_secret_versions(
+            path,
+            responses.iter().flatten().map(|(_, vault_key)| vault_key),
+        )?;
+    }
+
+    if let Some(path) = &config.keystore_report_path {
+        info!("Writing keystore report to {}", path.display());
+        write_keystore_report(
+            path,
+            responses.iter().flatten().map(|(_, vault_key)| vault_key),
+        )?;
+    }
+
+    let loaded_pubkeys: BTreeSet<String> = responses
+        .iter()
+        .flatten()
+        .map(|(pubkey, _)| pubkey.clone())
+        .collect();
+    let (written_pubkeys, changes, failed_imports) = match &outputs.keymanager_client {
+        Some(keymanager_client) => {
+            let (imported_pubkeys, failed_imports) =
+                output_keymanager(config, keymanager_client, responses).await?;
+            let changes = imported_pubkeys.len();
+            (imported_pubkeys, changes, failed_imports)
+        }
+        None => {
+            let (written_pubkeys, changes) =
+                output_files(config, responses, &requested_pubkeys).await?;
+            (written_pubkeys, changes, 0)
+        }
+    };
+
+    let mut missing_pubkeys = vec![];
+    if let Some(web3signer_client) = &outputs.web3signer_client {
+        if changes > 0 {
+            missing_pubkeys =
+                reload_web3signer(config, web3signer_client, &written_pubkeys).await?;
+        }
+    }
+
+    let mut failed_remotekeys = 0;
+    if !outputs.remotekeys_clients.is_empty() {
+        let pubkeys: BTreeSet<String> = loaded_pubkeys
+            .into_iter()
+            .filter(|pubkey| !missing_pubkeys.contains(pubkey))
+            .collect();
+        failed_remotekeys = sync_remote_keys(config, outputs, &pubkeys, &requested_pubkeys).await?;
+    }
 
     let end = Instant::now();
     let elapsed = end - start;
+    METRICS.load_duration.observe(elapsed.as_secs_f64());
     println!("Elapsed time: {:.2?}", elapsed);
 
-    Ok(())
+    if failed_responses > 0 {
+        return Err(anyhow!(
+            "Failed to retrieve {} private keys from Vault",
+            failed_responses
+        ));
+    }
+    if failed_imports > 0 {
+        return Err(anyhow!(
+            "Failed to import {} private keys through the Keymanager API",
+            failed_imports
+        ));
+    }
+    if !missing_pubkeys.is_empty() {
+        return Err(anyhow!(
+            "web3signer did not load {} written keys",
+            missing_pubkeys.len()
+        ));
+    }
+    if failed_remotekeys > 0 {
+        return Err(anyhow!(
+            "Failed to sync remote keys with {} validator clients",
+            failed_remotekeys
+        ));
+    }
+
+    Ok(changes)
 }
@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, Registry,
+    TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::key_store::{write_file, FilePermissions};
+
+#[cfg(test)]
+#[path = "./metrics_tests.rs"]
+mod metrics_tests;
+
+/// Metrics of the key loads, shared by the whole process
+pub static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics::new().expect("Invalid metrics"));
+
+pub struct Metrics {
+    registry: Registry,
+    pub keys_requested: IntCounter,
+    pub keys_fetched: IntCounter,
+    pub keys_failed: IntCounter,
+    pub keys_written: IntCounter,
+    pub vault_request_duration: HistogramVec,
+    pub vault_retries: IntCounter,
+    pub bytes_written: IntCounter,
+    pub load_duration: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("vault_loader".to_string()), None)?;
+        let metrics = Metrics {
+            keys_requested: IntCounter::new(
+                "keys_requested_total",
+                "Public keys requested from Vault",
+            )?,
+            keys_fetched: IntCounter::new(
+                "keys_fetched_total",
+                "Private keys fetched from Vault and verified",
+            )?,
+            keys_failed: IntCounter::new(
+                "keys_failed_total",
+                "Private keys that could not be fetched, verified or encrypted",
+            )?,
+            keys_written: IntCounter::new(
+                "keys_written_total",
+                "Private keys written to the key store or imported",
+            )?,
+            vault_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "vault_request_duration_seconds",
+                    "Latency of Vault requests by status code",
+                )
+                .buckets(exponential_buckets(0.005, 2.0, 12)?),
+                &["status"],
+            )?,
+            vault_retries: IntCounter::new(
+                "vault_retries_total",
+                "Private key fetches retried after a failure",
+            )?,
+            bytes_written: IntCounter::new(
+                "bytes_written_total",
+                "Bytes of key files written to the key store",
+            )?,
+            load_duration: Histogram::with_opts(
+                HistogramOpts::new("load_duration_seconds", "Duration of key loads")
+                    .buckets(exponential_buckets(0.5, 2.0, 12)?),
+            )?,
+            registry,
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.keys_requested.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.keys_fetched.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.keys_failed.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.keys_written.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.vault_request_duration.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.vault_retries.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.bytes_written.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.load_duration.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Records the latency of a Vault request, labelled by its status code or
+    /// by `error` when no response came back
+    pub fn observe_vault_request(&self, status: Option<StatusCode>, duration: Duration) {
+        let status = status.map_or_else(|| "error".to_string(), |s| s.as_u16().to_string());
+        self.vault_request_duration
+            .with_label_values(&[&status])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Encodes the metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    match METRICS.encode() {
+        Ok(metrics) => Ok(Response::builder()
+            .header("Content-Type", TextEncoder::new().format_type())
+            .body(Body::from(metrics))
+            .unwrap_or_default()),
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            Ok(response)
+        }
+    }
+}
+
+/// Serves the metrics on `/metrics` in the background until the process exits
+pub fn spawn_metrics_server(address: SocketAddr) -> Result<()> {
+    let server = Server::try_bind(&address)
+        .map_err(|e| anyhow!("Failed to listen on {}: {}", address, e))?
+        .serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(handle_request))
+        }));
+    info!("Serving metrics on http://{}/metrics", server.local_addr());
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Metrics server failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Writes the metrics to a node-exporter textfile collector file
+pub async fn write_metrics_textfile(path: &Path) -> Result<()> {
+    info!("Writing metrics to {}", path.display());
+    let permissions = FilePermissions {
+        mode: 0o644,
+        uid: None,
+        gid: None,
+    };
+    write_file(path, METRICS.encode()?.as_bytes(), &permissions).await
+}
@@ -0,0 +1,42 @@
+use super::*;
+use std::os::unix::fs::PermissionsExt;
+
+#[test]
+fn test_encode() {
+    METRICS.keys_requested.inc_by(2);
+    METRICS.observe_vault_request(Some(StatusCode::OK), Duration::from_millis(20));
+    METRICS.observe_vault_request(None, Duration::from_secs(1));
+    let metrics = METRICS.encode().unwrap();
+    assert!(metrics.contains("# TYPE vault_loader_keys_requested_total counter"));
+    assert!(metrics.contains("vault_loader_vault_request_duration_seconds_count{status=\"200\"}"));
+    assert!(metrics.contains("vault_loader_vault_request_duration_seconds_count{status=\"error\"}"));
+    assert!(metrics.contains("# TYPE vault_loader_load_duration_seconds histogram"));
+}
+
+#[tokio::test]
+async fn test_handle_request() {
+    let request = Request::get("/metrics").body(Body::empty()).unwrap();
+    let response = handle_request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("vault_loader_keys_written_total"));
+
+    let request = Request::get("/").body(Body::empty()).unwrap();
+    let response = handle_request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_write_metrics_textfile() {
+    let directory = std::env::temp_dir().join("vault-loader-test-metrics-textfile");
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("vault_loader.prom");
+
+    write_metrics_textfile(&path).await.unwrap();
+    let metrics = std::fs::read_to_string(&path).unwrap();
+    assert!(metrics.contains("vault_loader_bytes_written_total"));
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o644);
+    assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+}
@@ -0,0 +1,628 @@
+use crate::auth::{self, VaultAuth, VaultAuthMethod, VaultLoginResponse};
+use crate::config::Config;
+use crate::keystores::{RejectedVaultKey, VaultKey};
+use crate::metrics::METRICS;
+use anyhow::{anyhow, Context, Error, Result};
+use base64::{engine::general_purpose, Engine as _};
+use log::{error, info, warn};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Certificate, Client, ClientBuilder, Identity, Method, RequestBuilder, Response, StatusCode,
+    Url,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+#[cfg(test)]
+#[path = "./vault_tests.rs"]
+mod vault_tests;
+
+/// Longest wait between two attempts to refresh the Vault token
+const TOKEN_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct VaultClient {
+    http_client: Client,
+    config: Arc<Config>,
+    token: Arc<RwLock<VaultToken>>,
+}
+
+#[derive(Default)]
+struct VaultToken {
+    client_token: String,
+    error: Option<String>,
+    renewed: bool,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Default, Clone)]
+pub struct VaultTokenLookup {
+    #[serde(default)]
+    pub ttl: u64,
+    #[serde(default)]
+    pub renewable: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct VaultTokenLookupResponse {
+    data: VaultTokenLookup,
+}
+
+/// KV v2 version a secret was read at
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default, Clone)]
+pub struct SecretVersion {
+    pub version: u64,
+    #[serde(default)]
+    pub created_time: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum KvVersion {
+    V1,
+    V2,
+}
+
+/// KV secrets engine serving `vault_path`, and the API paths its secrets are
+/// read and listed from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct KvMount {
+    pub mount: String,
+    pub version: KvVersion,
+    pub data_path: String,
+    pub metadata_path: String,
+}
+
+impl KvMount {
+    /// Builds the mount from a `sys/internal/ui/mounts/<vault_path>` response.
+    /// A `vault_path` that already contains the KV v2 `data/` segment is kept
+    /// as is.
+    pub fn new(vault_path: &str, mount_info: &Value) -> Result<Self> {
+        let vault_path = vault_path.trim_matches('/');
+        let mount = mount_info["data"]["path"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Vault did not report a mount for {}", vault_path))?;
+        let engine = mount_info["data"]["type"].as_str().unwrap_or_default();
+        if engine != "kv" && engine != "generic" {
+            return Err(anyhow!(
+                "{} is mounted on a {} engine, not a KV engine",
+                vault_path,
+                engine
+            ));
+        }
+        let version = match mount_info["data"]["options"]["version"].as_str() {
+            Some("2") => KvVersion::V2,
+            _ => KvVersion::V1,
+        };
+        let relative_path = vault_path
+            .strip_prefix(mount.trim_end_matches('/'))
+            .unwrap_or_default()
+            .trim_start_matches('/');
+        let (data_path, metadata_path) = match version {
+            KvVersion::V1 => (vault_path.to_string(), vault_path.to_string()),
+            KvVersion::V2 if relative_path.starts_with("data/") || relative_path == "data" => (
+                vault_path.to_string(),
+                format!(
+                    "{}metadata{}",
+                    mount,
+                    relative_path.strip_prefix("data").unwrap_or_default()
+                ),
+            ),
+            KvVersion::V2 => (
+                format!("{}data/{}", mount, relative_path)
+                    .trim_end_matches('/')
+                    .to_string(),
+                format!("{}metadata/{}", mount, relative_path)
+                    .trim_end_matches('/')
+                    .to_string(),
+            ),
+        };
+        Ok(KvMount {
+            mount: mount.to_string(),
+            version,
+            data_path,
+            metadata_path,
+        })
+    }
+
+    /// Expands a `vault_secret_path_template` for a public key found in
+    /// `folder`, relative to `vault_path`
+    pub fn secret_path(&self, template: &str, folder: &str, pubkey: &str) -> String {
+        let vault_path = format!("{}/{}", self.data_path, folder);
+        template
+            .replace("{vault_path}", vault_path.trim_end_matches('/'))
+            .replace("{pubkey}", pubkey)
+    }
+
+    /// Extracts the secret key/value pairs from a read response
+    pub fn secret_data<'a>(&self, response: &'a Value) -> &'a Value {
+        match self.version {
+            KvVersion::V1 => &response["data"],
+            KvVersion::V2 => &response["data"]["data"],
+        }
+    }
+
+    /// Extracts the version of a KV v2 read response, rejecting versions that
+    /// were soft-deleted or destroyed.
+    pub fn secret_version(&self, response: &Value) -> Result<Option<SecretVersion>> {
+        if self.version == KvVersion::V1 {
+            return Ok(None);
+        }
+        let metadata = &response["data"]["metadata"];
+        let version = match metadata["version"].as_u64() {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+        if metadata["destroyed"].as_bool().unwrap_or_default() {
+            return Err(anyhow!(RejectedVaultKey(format!(
+                "version {} was destroyed",
+                version
+            ))));
+        }
+        match metadata["deletion_time"].as_str() {
+            Some(deletion_time) if !deletion_time.is_empty() => Err(anyhow!(RejectedVaultKey(
+                format!("version {} was deleted at {}", version, deletion_time)
+            ))),
+            _ => Ok(Some(SecretVersion {
+                version,
+                created_time: metadata["created_time"].as_str().map(str::to_string),
+            })),
+        }
+    }
+}
+
+impl VaultClient {
+    pub fn get(&self, url: Url) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        self.http_client
+            .request(method, url)
+            .header("x-vault-token", self.client_token())
+    }
+
+    /// Set once the token could neither be renewed nor obtained again, after
+    /// which every request made with this client is bound to be denied.
+    pub fn token_error(&self) -> Option<String> {
+        self.token.read().unwrap().error.clone()
+    }
+
+    /// Whether the token is renewed in the background, so that a request it
+    /// was denied for may succeed with the next token
+    fn is_renewed(&self) -> bool {
+        self.token.read().unwrap().renewed
+    }
+
+    fn client_token(&self) -> String {
+        self.token.read().unwrap().client_token.clone()
+    }
+
+    fn set_client_token(&self, client_token: String) {
+        self.token.write().unwrap().client_token = client_token;
+    }
+
+    fn set_token_error(&self, error: String) {
+        self.token.write().unwrap().error = Some(error);
+    }
+
+    pub async fn lookup_token(&self) -> Result<VaultTokenLookup> {
+        let url = Url::parse(&format!(
+            "{}/v1/auth/token/lookup-self",
+            &self.config.vault_addr
+        ))?;
+        let response = send_request(self.get(url)).await?.error_for_status()?;
+        Ok(response.json::<VaultTokenLookupResponse>().await?.data)
+    }
+
+    async fn renew_token(&self) -> Result<VaultAuth> {
+        let url = Url::parse(&format!(
+            "{}/v1/auth/token/renew-self",
+            &self.config.vault_addr
+        ))?;
+        let response = send_request(self.request(Method::POST, url))
+            .await?
+            .error_for_status()?;
+        Ok(response.json::<VaultLoginResponse>().await?.auth)
+    }
+
+    /// Extends the lifetime of the current token, or replaces it with a new
+    /// one when it reached its max TTL and the auth method can log in again.
+    pub async fn refresh_token(&self, lookup: &VaultTokenLookup) -> Result<VaultTokenLookup> {
+        if lookup.renewable {
+            match self.renew_token().await {
+                Ok(vault_auth) if vault_auth.lease_duration >= lookup.ttl => {
+                    info!("Vault token renewed for {}s", vault_auth.lease_duration);
+                    return Ok(VaultTokenLookup {
+                        ttl: vault_auth.lease_duration,
+                        renewable: vault_auth.renewable,
+                    });
+                }
+                Ok(vault_auth) => {
+                    warn!(
+                        "Vault token renewed for {}s only, it is reaching its max TTL",
+                        vault_auth.lease_duration
+                    );
+                    if self.config.vault_auth_method == VaultAuthMethod::Token
+                        && vault_auth.lease_duration > 0
+                    {
+                        return Ok(VaultTokenLookup {
+                            ttl: vault_auth.lease_duration,
+                            renewable: vault_auth.renewable,
+                        });
+                    }
+                }
+                Err(error) => warn!("Failed to renew Vault token: {}", error),
+            }
+        }
+
+        if self.config.vault_auth_method == VaultAuthMethod::Token {
+            return Err(anyhow!(
+                "Vault token cannot be renewed and the token auth method cannot log in again"
+            ));
+        }
+
+        info!(
+            "Logging in to Vault again with the {} method",
+            self.config.vault_auth_method
+        );
+        let vault_auth = auth::login(&self.config, &self.http_client).await?;
+        self.set_client_token(vault_auth.client_token);
+        match self.lookup_token().await {
+            Ok(lookup) => Ok(lookup),
+            Err(error) => {
+                // The new token is valid, its lease is known from the login
+                warn!("Failed to look up the new Vault token: {}", error);
+                Ok(VaultTokenLookup {
+                    ttl: vault_auth.lease_duration,
+                    renewable: vault_auth.renewable,
+                })
+            }
+        }
+    }
+
+    /// Detects the KV engine version of `vault_path`. When the mount cannot be
+    /// looked up, `vault_path` is assumed to be a complete KV v2 data path.
+    pub async fn kv_mount(&self, vault_path: &str) -> Result<KvMount> {
+        let url = Url::parse(&format!(
+            "{}/v1/sys/internal/ui/mounts/{}",
+            &self.config.vault_addr,
+            vault_path.trim_matches('/')
+        ))?;
+        match send_request(self.get(url)).await?.error_for_status() {
+            Ok(response) => KvMount::new(vault_path, &response.json::<Value>().await?),
+            Err(error) => {
+                warn!(
+                    "Failed to detect the KV engine version of {}, assuming a KV v2 data path: {}",
+                    vault_path, error
+                );
+                let data_path = vault_path.trim_matches('/').to_string();
+                Ok(KvMount {
+                    mount: String::new(),
+                    version: KvVersion::V2,
+                    metadata_path: data_path.replacen("/data/", "/metadata/", 1),
+                    data_path,
+                })
+            }
+        }
+    }
+
+    /// Lists the public keys stored under the KV mount path, descending into
+    /// sub-folders that are not public keys themselves. Each public key is
+    /// mapped to the sub-folder it was found in, relative to `vault_path`.
+    pub async fn list_public_keys(&self, kv_mount: &KvMount) -> Result<BTreeMap<String, String>> {
+        let mut pubkeys = BTreeMap::new();
+        let mut folders = vec![String::new()];
+        while let Some(folder) = folders.pop() {
+            let url = Url::parse(&format!(
+                "{}/v1/{}/{}?list=true",
+                &self.config.vault_addr, &kv_mount.metadata_path, folder
+            ))?;
+            let response = send_request(self.get(url)).await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                continue;
+            }
+            let response = response.error_for_status()?.json::<Value>().await?;
+            let keys = response["data"]["keys"].as_array().ok_or_else(|| {
+                anyhow!(
+                    "Vault did not return a list of keys for {}",
+                    kv_mount.metadata_path
+                )
+            })?;
+            for key in keys.iter().filter_map(Value::as_str) {
+                let name = key.trim_end_matches('/');
+                if is_bls_pubkey(name) {
+                    pubkeys.insert(name.to_string(), folder.clone());
+                } else if key.ends_with('/') {
+                    folders.push(format!("{}{}", folder, key));
+                }
+            }
+        }
+        Ok(pubkeys)
+    }
+
+    /// Decrypts Vault Transit ciphertexts with `vault_transit_key`, returning
+    /// one result per ciphertext
+    pub async fn transit_decrypt(&self, ciphertexts: &[String]) -> Result<Vec<Result<String>>> {
+        let transit_key = self.config.vault_transit_key.as_ref().ok_or_else(|| {
+            anyhow!("vault_transit_key must be set to decrypt transit ciphertext")
+        })?;
+        let url = Url::parse(&format!(
+            "{}/v1/{}/decrypt/{}",
+            &self.config.vault_addr, &self.config.vault_transit_mount, transit_key
+        ))?;
+        let batch_input: Vec<Value> = ciphertexts
+            .iter()
+            .map(|ciphertext| json!({ "ciphertext": ciphertext }))
+            .collect();
+        let response = send_request(
+            self.request(Method::POST, url)
+                .json(&json!({ "batch_input": batch_input })),
+        )
+        .await?;
+        let status = response.status();
+        let response = response.json::<Value>().await.unwrap_or_default();
+        let batch_results = match response["data"]["batch_results"].as_array() {
+            Some(batch_results) if batch_results.len() == ciphertexts.len() => batch_results,
+            _ => return Err(anyhow!("Transit decryption failed with status {}", status)),
+        };
+        Ok(batch_results
+            .iter()
+            .map(
+                |result| match (result["plaintext"].as_str(), result["error"].as_str()) {
+                    (_, Some(error)) if !error.is_empty() => {
+                        Err(anyhow!("Transit decryption failed: {}", error))
+                    }
+                    (Some(plaintext), _) => Ok(String::from_utf8(
+                        general_purpose::STANDARD.decode(plaintext)?,
+                    )?),
+                    _ => Err(anyhow!("Transit decryption returned no plaintext")),
+                },
+            )
+            .collect())
+    }
+
+    /// Decrypts every Transit ciphertext held by `vault_keys` in place, in
+    /// batches of `vault_transit_batch_size`. Returns the error of each key
+    /// that could not be decrypted.
+    pub async fn decrypt_transit_secrets(
+        &self,
+        vault_keys: Vec<&mut VaultKey>,
+    ) -> HashMap<String, Error> {
+        let mut failed = HashMap::new();
+        let mut fields = vec![];
+        for vault_key in vault_keys {
+            let pubkey = vault_key.pubkey.clone();
+            for field in
+                vault_key.transit_ciphertexts_mut(self.config.vault_transit_raw_unencrypted_key)
+            {
+                fields.push((pubkey.clone(), field));
+            }
+        }
+        if fields.is_empty() {
+            return failed;
+        }
+
+        info!("Decrypting {} transit ciphertexts", fields.len());
+        for batch in fields.chunks_mut(max(self.config.vault_transit_batch_size, 1)) {
+            let ciphertexts: Vec<String> =
+                batch.iter().map(|(_, field)| field.to_string()).collect();
+            match self.transit_decrypt(&ciphertexts).await {
+                Ok(results) => {
+                    for ((pubkey, field), result) in batch.iter_mut().zip(results) {
+                        match result {
+                            Ok(plaintext) => **field = plaintext,
+                            Err(error) => {
+                                failed.insert(pubkey.clone(), error);
+                            }
+                        }
+                    }
+                }
+                Err(error) => {
+                    error!("Failed to decrypt transit ciphertexts: {}", error);
+                    for (pubkey, _) in batch.iter() {
+                        failed.insert(pubkey.clone(), anyhow!("{}", error));
+                    }
+                }
+            }
+        }
+        failed
+    }
+
+    /// Refreshes the token once two thirds of its TTL have passed. Failed
+    /// refreshes are retried with a backoff until the token actually expires.
+    fn spawn_token_renewal(&self, mut lookup: VaultTokenLookup) {
+        self.token.write().unwrap().renewed = true;
+        let vault_client = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let expires_at = Instant::now() + Duration::from_secs(lookup.ttl);
+                sleep(Duration::from_secs(max(lookup.ttl * 2 / 3, 1))).await;
+                let mut backoff = Duration::from_secs(1);
+                lookup = loop {
+                    match vault_client.refresh_token(&lookup).await {
+                        Ok(refreshed) => break refreshed,
+                        Err(error) => {
+                            let remaining = expires_at.saturating_duration_since(Instant::now());
+                            if remaining.is_zero() {
+                                error!("Failed to refresh Vault token: {}", error);
+                                vault_client
+                                    .set_token_error(format!("Vault token expired: {}", error));
+                                return;
+                            }
+                            warn!(
+                                "Failed to refresh Vault token, expiring in {}s, retrying in {}s: {}",
+                                remaining.as_secs(),
+                                min(backoff, remaining).as_secs(),
+                                error
+                            );
+                            sleep(min(backoff, remaining)).await;
+                            backoff = min(backoff * 2, TOKEN_RETRY_MAX_BACKOFF);
+                        }
+                    }
+                };
+            }
+        });
+    }
+}
+
+/// Whether `name` looks like a 0x-prefixed, hex-encoded 48-byte BLS public key
+pub fn is_bls_pubkey(name: &str) -> bool {
+    match name.strip_prefix("0x") {
+        Some(hex) => hex.len() == 96 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+pub async fn build_vault_client(config: &Config) -> Result<VaultClient> {
+    info!(
+        "Authenticating to Vault with the {} method",
+        config.vault_auth_method
+    );
+    let http_client = build_http_client(config)?;
+    let vault_auth = auth::login(config, &http_client).await?;
+    info!("Vault token obtained successfully");
+
+    let vault_client = VaultClient {
+        http_client,
+        config: Arc::new(config.clone()),
+        token: Arc::new(RwLock::new(VaultToken {
+            client_token: vault_auth.client_token,
+            ..Default::default()
+        })),
+    };
+
+    match vault_client.lookup_token().await {
+        Ok(lookup) if lookup.ttl > 0 => {
+            info!(
+                "Vault token expires in {}s, renewing it in the background",
+                lookup.ttl
+            );
+            vault_client.spawn_token_renewal(lookup);
+        }
+        Ok(_) => info!("Vault token does not expire"),
+        Err(error) => warn!(
+            "Failed to look up Vault token, it will not be renewed: {}",
+            error
+        ),
+    }
+
+    Ok(vault_client)
+}
+
+fn build_http_client(config: &Config) -> Result<Client> {
+    let mut headers = HeaderMap::new();
+    if let Some(vault_namespace) = &config.vault_namespace {
+        info!("Using Vault namespace {}", vault_namespace);
+        headers.insert(
+            HeaderName::from_static("x-vault-namespace"),
+            HeaderValue::from_str(vault_namespace)?,
+        );
+    }
+
+    match tls_client_builder(config)?.default_headers(headers).build() {
+        Ok(vault_client) => Ok(vault_client),
+        Err(error) => {
+            error!("Failed to create vault client: {}", error);
+            Err(error).context("Failed to create vault client")
+        }
+    }
+}
+
+/// HTTP client builder with the TLS options of the Vault client, which
+/// web3signer and validator clients are reached with too
+pub fn tls_client_builder(config: &Config) -> Result<ClientBuilder> {
+    info!("Checking TLS configuration");
+    let vault_cacert = config.vault_cacert.as_ref().and_then(|vault_cacert| {
+        if let Ok(vault_cacert) = fs::read(vault_cacert) {
+            info!("CA certificate provided, TLS authentication enabled");
+            Some(Certificate::from_pem(&vault_cacert).ok()).flatten()
+        } else {
+            info!("CA certificate not provided, TLS authentication disabled");
+            None
+        }
+    });
+
+    let vault_client_auth = if let (Some(vault_client_cert), Some(vault_client_key)) =
+        (&config.vault_client_cert, &config.vault_client_key)
+    {
+        if let (Ok(vault_client_cert), Ok(vault_client_key)) = (
+            fs::read_to_string(vault_client_cert),
+            fs::read_to_string(vault_client_key),
+        ) {
+            info!("Client certificate and key provided, TLS authentication enabled");
+            Some(Identity::from_pem(
+                (vault_client_cert + &vault_client_key).as_bytes(),
+            )?)
+        } else {
+            info!("Client certificate and key not provided, TLS authentication disabled");
+            None
+        }
+    } else {
+        None
+    };
+
+    if let (Some(vault_client_auth), Some(vault_cacert)) = (vault_client_auth, vault_cacert) {
+        info!("Building client with TLS authentication");
+        Ok(ClientBuilder::new()
+            .add_root_certificate(vault_cacert)
+            .identity(vault_client_auth)
+            .use_rustls_tls())
+    } else {
+        info!("Building client without TLS authentication");
+        Ok(ClientBuilder::new())
+    }
+}
+
+/// Sends a Vault request, recording its latency by status code
+pub async fn send_request(request: RequestBuilder) -> reqwest::Result<Response> {
+    let start = Instant::now();
+    let response = request.send().await;
+    METRICS.observe_vault_request(
+        response.as_ref().ok().map(Response::status),
+        start.elapsed(),
+    );
+    response
+}
+
+/// Reads the secret of a public key. A missing secret or a deleted version
+/// rejects the key, as does a 403 unless the token is renewed in the
+/// background. Other errors are left for the caller to retry.
+pub async fn get_vault_key(
+    vault_client: &VaultClient,
+    kv_mount: &KvMount,
+    url: Url,
+    pubkey: &str,
+) -> Result<VaultKey, Error> {
+    let response = send_request(vault_client.get(url)).await?;
+    let status = response.status();
+    if status == StatusCode::NOT_FOUND {
+        // A deleted KV v2 version comes back as a 404 that still explains why
+        if let Ok(response) = response.json::<Value>().await {
+            kv_mount.secret_version(&response)?;
+        }
+        return Err(anyhow!(RejectedVaultKey(format!(
+            "Vault has no secret for {}",
+            pubkey
+        ))));
+    }
+    if status == StatusCode::FORBIDDEN && !vault_client.is_renewed() {
+        return Err(anyhow!(RejectedVaultKey(format!(
+            "Vault denied access to the secret of {} and the token is not renewed",
+            pubkey
+        ))));
+    }
+    let response = response.error_for_status()?.json::<Value>().await?;
+    let version = kv_mount.secret_version(&response)?;
+    let mut vault_key = VaultKey::new(
+        kv_mount.secret_data(&response).clone(),
+        pubkey,
+        &vault_client.config.vault_secret_fields,
+    )?;
+    vault_key.version = version;
+    Ok(vault_key)
+}
@@ -0,0 +1,632 @@
+use super::*;
+use serde_json::json;
+use wiremock::matchers::{body_json, header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn vault_client(config: Config, client_token: &str) -> VaultClient {
+    VaultClient {
+        http_client: Client::new(),
+        config: Arc::new(config),
+        token: Arc::new(RwLock::new(VaultToken {
+            client_token: client_token.to_string(),
+            ..Default::default()
+        })),
+    }
+}
+
+#[tokio::test]
+async fn test_build_vault_client_token() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/auth/token/lookup-self"))
+        .and(header("x-vault-token", "s.static"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "data": { "ttl": 0, "renewable": false } })),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let token_path = std::env::temp_dir().join("vault-loader-test-vault-token");
+    fs::write(&token_path, "s.static").unwrap();
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_token_path: Some(token_path.clone()),
+        ..Default::default()
+    };
+    let vault_client = build_vault_client(&config).await;
+    fs::remove_file(token_path).unwrap();
+    let vault_client = vault_client.unwrap();
+    assert_eq!(vault_client.client_token(), "s.static");
+    assert_eq!(vault_client.token_error(), None);
+}
+
+#[tokio::test]
+async fn test_refresh_token_renew() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/token/renew-self"))
+        .and(header("x-vault-token", "s.renewable"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "auth": { "client_token": "s.renewable", "lease_duration": 600, "renewable": true }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.renewable");
+    let lookup = VaultTokenLookup {
+        ttl: 200,
+        renewable: true,
+    };
+    assert_eq!(
+        vault_client.refresh_token(&lookup).await.unwrap(),
+        VaultTokenLookup {
+            ttl: 600,
+            renewable: true,
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_token_renewal_retries() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/token/renew-self"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/token/renew-self"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "auth": { "client_token": "s.renewable", "lease_duration": 3600, "renewable": true }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.renewable");
+    vault_client.spawn_token_renewal(VaultTokenLookup {
+        ttl: 3,
+        renewable: true,
+    });
+    sleep(Duration::from_secs(4)).await;
+    assert_eq!(vault_client.token_error(), None);
+}
+
+#[tokio::test]
+async fn test_refresh_token_login_again_at_max_ttl() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/token/renew-self"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "auth": { "client_token": "s.old", "lease_duration": 10, "renewable": true }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/approle/login"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "auth": { "client_token": "s.new", "lease_duration": 600, "renewable": true }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v1/auth/token/lookup-self"))
+        .and(header("x-vault-token", "s.new"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "data": { "ttl": 600, "renewable": true } })),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_auth_method: VaultAuthMethod::AppRole,
+        vault_role_id: Some("role".to_string()),
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.old");
+    let lookup = VaultTokenLookup {
+        ttl: 600,
+        renewable: true,
+    };
+    assert_eq!(
+        vault_client.refresh_token(&lookup).await.unwrap(),
+        VaultTokenLookup {
+            ttl: 600,
+            renewable: true,
+        }
+    );
+    assert_eq!(vault_client.client_token(), "s.new");
+}
+
+#[tokio::test]
+async fn test_refresh_token_not_renewable() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/token/renew-self"))
+        .respond_with(ResponseTemplate::new(403))
+        .expect(0)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.batch");
+    let lookup = VaultTokenLookup {
+        ttl: 600,
+        renewable: false,
+    };
+    assert!(vault_client.refresh_token(&lookup).await.is_err());
+}
+
+#[tokio::test]
+async fn test_build_vault_client_namespace() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/auth/approle/login"))
+        .and(header("x-vault-namespace", "admin/goerli"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "auth": { "client_token": "s.namespaced", "lease_duration": 0, "renewable": false }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v1/auth/token/lookup-self"))
+        .and(header("x-vault-namespace", "admin/goerli"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({ "data": { "ttl": 0, "renewable": false } })),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v1/ethereum/data/keys/0xabcd/vkey"))
+        .and(header("x-vault-namespace", "admin/goerli"))
+        .and(header("x-vault-token", "s.namespaced"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "data": { "raw_unencrypted_key": "0x01" } }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_namespace: Some("admin/goerli".to_string()),
+        vault_auth_method: VaultAuthMethod::AppRole,
+        vault_role_id: Some("role".to_string()),
+        ..Default::default()
+    };
+    let vault_client = build_vault_client(&config).await.unwrap();
+    let url = Url::parse(&format!(
+        "{}/v1/ethereum/data/keys/0xabcd/vkey",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let kv_mount = KvMount {
+        mount: "ethereum/".to_string(),
+        version: KvVersion::V2,
+        data_path: "ethereum/data/keys".to_string(),
+        metadata_path: "ethereum/metadata/keys".to_string(),
+    };
+    let vault_key = get_vault_key(&vault_client, &kv_mount, url, "0xabcd")
+        .await
+        .unwrap();
+    assert_eq!(vault_key.raw_unencrypted_key, Some("0x01".to_string()));
+}
+
+#[test]
+fn test_kv_mount_v2_logical_path() {
+    let mount_info = json!({
+        "data": { "path": "ethereum/", "type": "kv", "options": { "version": "2" } }
+    });
+    let kv_mount = KvMount::new("ethereum/goerli/keys", &mount_info).unwrap();
+    assert_eq!(
+        kv_mount,
+        KvMount {
+            mount: "ethereum/".to_string(),
+            version: KvVersion::V2,
+            data_path: "ethereum/data/goerli/keys".to_string(),
+            metadata_path: "ethereum/metadata/goerli/keys".to_string(),
+        }
+    );
+    let response = json!({ "data": { "data": { "vkey": "a" }, "metadata": { "version": 1 } } });
+    assert_eq!(kv_mount.secret_data(&response), &json!({ "vkey": "a" }));
+}
+
+#[test]
+fn test_kv_mount_v2_data_path() {
+    let mount_info = json!({
+        "data": { "path": "ethereum/", "type": "kv", "options": { "version": "2" } }
+    });
+    let kv_mount = KvMount::new("ethereum/data/goerli/keys", &mount_info).unwrap();
+    assert_eq!(kv_mount.data_path, "ethereum/data/goerli/keys");
+    assert_eq!(kv_mount.metadata_path, "ethereum/metadata/goerli/keys");
+}
+
+#[test]
+fn test_kv_mount_v1() {
+    let mount_info = json!({
+        "data": { "path": "secret/", "type": "kv", "options": null }
+    });
+    let kv_mount = KvMount::new("secret/goerli/keys/", &mount_info).unwrap();
+    assert_eq!(kv_mount.version, KvVersion::V1);
+    assert_eq!(kv_mount.data_path, "secret/goerli/keys");
+    assert_eq!(kv_mount.metadata_path, "secret/goerli/keys");
+    let response = json!({ "data": { "vkey": "a" } });
+    assert_eq!(kv_mount.secret_data(&response), &json!({ "vkey": "a" }));
+}
+
+#[test]
+fn test_kv_mount_not_kv() {
+    let mount_info = json!({
+        "data": { "path": "transit/", "type": "transit", "options": null }
+    });
+    assert!(KvMount::new("transit/keys", &mount_info).is_err());
+}
+
+#[tokio::test]
+async fn test_kv_mount_detection() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/sys/internal/ui/mounts/ethereum/goerli/keys"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "path": "ethereum/", "type": "kv", "options": { "version": "2" } }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.token");
+    let kv_mount = vault_client.kv_mount("ethereum/goerli/keys").await.unwrap();
+    assert_eq!(kv_mount.data_path, "ethereum/data/goerli/keys");
+
+    let kv_mount = vault_client.kv_mount("ethereum/data/other").await.unwrap();
+    assert_eq!(kv_mount.version, KvVersion::V2);
+    assert_eq!(kv_mount.data_path, "ethereum/data/other");
+}
+
+#[test]
+fn test_secret_version() {
+    let kv_mount = KvMount {
+        mount: "ethereum/".to_string(),
+        version: KvVersion::V2,
+        data_path: "ethereum/data/keys".to_string(),
+        metadata_path: "ethereum/metadata/keys".to_string(),
+    };
+    let response = json!({ "data": { "data": {}, "metadata": {
+        "version": 3, "created_time": "2023-05-01T10:00:00Z", "deletion_time": "", "destroyed": false
+    } } });
+    assert_eq!(
+        kv_mount.secret_version(&response).unwrap(),
+        Some(SecretVersion {
+            version: 3,
+            created_time: Some("2023-05-01T10:00:00Z".to_string()),
+        })
+    );
+
+    let response = json!({ "data": { "data": null, "metadata": {
+        "version": 2, "deletion_time": "2023-05-02T10:00:00Z", "destroyed": false
+    } } });
+    let error = kv_mount.secret_version(&response).unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+
+    let response = json!({ "data": { "data": null, "metadata": {
+        "version": 1, "deletion_time": "", "destroyed": true
+    } } });
+    let error = kv_mount.secret_version(&response).unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+
+    let kv_mount = KvMount {
+        version: KvVersion::V1,
+        ..kv_mount
+    };
+    assert_eq!(
+        kv_mount.secret_version(&json!({ "data": {} })).unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_get_vault_key_pinned_version() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/ethereum/data/keys/0xabcd/vkey"))
+        .and(query_param("version", "2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": {
+                "data": { "raw_unencrypted_key": "0x02" },
+                "metadata": { "version": 2, "created_time": "2023-05-01T10:00:00Z", "deletion_time": "", "destroyed": false }
+            }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v1/ethereum/data/keys/0xabcd/vkey"))
+        .and(query_param("version", "1"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "data": {
+                "data": null,
+                "metadata": { "version": 1, "created_time": "2023-04-01T10:00:00Z", "deletion_time": "2023-04-02T10:00:00Z", "destroyed": false }
+            }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.token");
+    let kv_mount = KvMount {
+        mount: "ethereum/".to_string(),
+        version: KvVersion::V2,
+        data_path: "ethereum/data/keys".to_string(),
+        metadata_path: "ethereum/metadata/keys".to_string(),
+    };
+
+    let url = Url::parse(&format!(
+        "{}/v1/ethereum/data/keys/0xabcd/vkey?version=2",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let vault_key = get_vault_key(&vault_client, &kv_mount, url, "0xabcd")
+        .await
+        .unwrap();
+    assert_eq!(vault_key.raw_unencrypted_key, Some("0x02".to_string()));
+    assert_eq!(vault_key.version.unwrap().version, 2);
+
+    let url = Url::parse(&format!(
+        "{}/v1/ethereum/data/keys/0xabcd/vkey?version=1",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let error = get_vault_key(&vault_client, &kv_mount, url, "0xabcd")
+        .await
+        .unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+}
+
+#[tokio::test]
+async fn test_get_vault_key_client_error() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/ethereum/data/keys/0xabcd/vkey"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({ "errors": [] })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v1/ethereum/data/keys/0xef01/vkey"))
+        .respond_with(ResponseTemplate::new(403))
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.token");
+    let kv_mount = KvMount {
+        mount: "ethereum/".to_string(),
+        version: KvVersion::V2,
+        data_path: "ethereum/data/keys".to_string(),
+        metadata_path: "ethereum/metadata/keys".to_string(),
+    };
+
+    let url = Url::parse(&format!(
+        "{}/v1/ethereum/data/keys/0xabcd/vkey",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let error = get_vault_key(&vault_client, &kv_mount, url, "0xabcd")
+        .await
+        .unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+
+    let url = Url::parse(&format!(
+        "{}/v1/ethereum/data/keys/0xef01/vkey",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let error = get_vault_key(&vault_client, &kv_mount, url.clone(), "0xef01")
+        .await
+        .unwrap_err();
+    assert!(error.is::<RejectedVaultKey>());
+
+    // A renewed token may be replaced in the meantime, so the read is retried
+    vault_client.token.write().unwrap().renewed = true;
+    let error = get_vault_key(&vault_client, &kv_mount, url, "0xef01")
+        .await
+        .unwrap_err();
+    assert!(!error.is::<RejectedVaultKey>());
+}
+
+#[test]
+fn test_is_bls_pubkey() {
+    assert!(is_bls_pubkey("0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2"));
+    assert!(!is_bls_pubkey("8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2"));
+    assert!(!is_bls_pubkey("0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136"));
+    assert!(!is_bls_pubkey("0xz000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2"));
+    assert!(!is_bls_pubkey("shard-1"));
+}
+
+#[tokio::test]
+async fn test_list_public_keys() {
+    let pubkey_1 = "0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2";
+    let pubkey_2 = "0x80034e0023d717adfb048eb867b6f2c01d079a917be06afb95471e3d82df25818103b30061c6f50a51d59653d902d0f8";
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/v1/ethereum/metadata/keys/"))
+        .and(query_param("list", "true"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "keys": [format!("{}/", pubkey_1), "shard-1/", "README"] }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v1/ethereum/metadata/keys/shard-1/"))
+        .and(query_param("list", "true"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "keys": [format!("{}/", pubkey_2), "empty/"] }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/v1/ethereum/metadata/keys/shard-1/empty/"))
+        .respond_with(ResponseTemplate::new(404))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.token");
+    let kv_mount = KvMount {
+        mount: "ethereum/".to_string(),
+        version: KvVersion::V2,
+        data_path: "ethereum/data/keys".to_string(),
+        metadata_path: "ethereum/metadata/keys".to_string(),
+    };
+    let pubkeys = vault_client.list_public_keys(&kv_mount).await.unwrap();
+    assert_eq!(
+        pubkeys,
+        BTreeMap::from([
+            (pubkey_1.to_string(), String::new()),
+            (pubkey_2.to_string(), "shard-1/".to_string()),
+        ])
+    );
+}
+
+#[test]
+fn test_secret_path() {
+    let kv_mount = KvMount {
+        mount: "ethereum/".to_string(),
+        version: KvVersion::V2,
+        data_path: "ethereum/data/keys".to_string(),
+        metadata_path: "ethereum/metadata/keys".to_string(),
+    };
+    assert_eq!(
+        kv_mount.secret_path("{vault_path}/{pubkey}/vkey", "", "0xabcd"),
+        "ethereum/data/keys/0xabcd/vkey"
+    );
+    assert_eq!(
+        kv_mount.secret_path("{vault_path}/{pubkey}", "shard-1/", "0xabcd"),
+        "ethereum/data/keys/shard-1/0xabcd"
+    );
+}
+
+#[tokio::test]
+async fn test_decrypt_transit_secrets() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/transit/decrypt/keystores"))
+        .and(body_json(json!({ "batch_input": [
+            { "ciphertext": "vault:v1:first" },
+            { "ciphertext": "vault:v1:second" }
+        ] })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "data": { "batch_results": [
+                { "plaintext": "cGFzc3dvcmQx" },
+                { "plaintext": "cGFzc3dvcmQy" }
+            ] }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/v1/transit/decrypt/keystores"))
+        .and(body_json(json!({ "batch_input": [
+            { "ciphertext": "vault:v1:third" }
+        ] })))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+            "data": { "batch_results": [ { "error": "cipher: message authentication failed" } ] }
+        })))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let config = Config {
+        vault_addr: mock_server.uri(),
+        vault_transit_key: Some("keystores".to_string()),
+        vault_transit_mount: "transit".to_string(),
+        vault_transit_batch_size: 2,
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.token");
+    let mut vault_keys: Vec<VaultKey> = ["first", "second", "third"]
+        .iter()
+        .map(|name| VaultKey {
+            pubkey: name.to_string(),
+            password: Some(format!("vault:v1:{}", name)),
+            vkey: Some("keystore".to_string()),
+            ..Default::default()
+        })
+        .collect();
+    vault_keys.push(VaultKey {
+        pubkey: "plaintext".to_string(),
+        password: Some("password".to_string()),
+        ..Default::default()
+    });
+
+    let failed = vault_client
+        .decrypt_transit_secrets(vault_keys.iter_mut().collect())
+        .await;
+    assert_eq!(failed.len(), 1);
+    assert!(failed.contains_key("third"));
+    assert_eq!(vault_keys[0].password, Some("password1".to_string()));
+    assert_eq!(vault_keys[1].password, Some("password2".to_string()));
+    assert_eq!(vault_keys[2].password, Some("vault:v1:third".to_string()));
+    assert_eq!(vault_keys[3].password, Some("password".to_string()));
+}
+
+#[tokio::test]
+async fn test_decrypt_transit_secrets_without_key() {
+    let config = Config {
+        vault_addr: "http://127.0.0.1:1".to_string(),
+        ..Default::default()
+    };
+    let vault_client = vault_client(config, "s.token");
+    let mut vault_key = VaultKey {
+        pubkey: "0xabcd".to_string(),
+        password: Some("vault:v1:abcd".to_string()),
+        ..Default::default()
+    };
+    let failed = vault_client
+        .decrypt_transit_secrets(vec![&mut vault_key])
+        .await;
+    assert!(failed.contains_key("0xabcd"));
+}
@@ -0,0 +1,104 @@
+use crate::config::Config;
+use crate::eip2335::normalize_pubkey;
+use crate::vault::tls_client_builder;
+use anyhow::{anyhow, Context, Result};
+use log::{error, info, warn};
+use reqwest::{Client, Url};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+#[cfg(test)]
+#[path = "./web3signer_tests.rs"]
+mod web3signer_tests;
+
+/// Client of the web3signer HTTP API that tells it to load written keys
+#[derive(Clone)]
+pub struct Web3signerClient {
+    http_client: Client,
+    url: Url,
+}
+
+impl Web3signerClient {
+    pub fn new(http_client: Client, url: &str) -> Result<Self> {
+        // Endpoints are joined to the URL, which must then end with a slash
+        let mut url = Url::parse(url)?;
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Ok(Web3signerClient { http_client, url })
+    }
+
+    /// Asks web3signer to load the keys of its key store again
+    pub async fn reload(&self) -> Result<()> {
+        let url = self.url.join("reload")?;
+        info!("Reloading web3signer keys at {}", url);
+        let response = self.http_client.post(url).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            error!("web3signer reload failed with status {}", status);
+            return Err(anyhow!("web3signer reload failed with status {}", status));
+        }
+        Ok(())
+    }
+
+    /// Public keys web3signer currently signs for
+    pub async fn public_keys(&self) -> Result<BTreeSet<String>> {
+        let url = self.url.join("api/v1/eth2/publicKeys")?;
+        let pubkeys: Vec<String> = self
+            .http_client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("Failed to parse web3signer public keys")?;
+        Ok(pubkeys
+            .iter()
+            .map(|pubkey| normalize_pubkey(pubkey))
+            .collect())
+    }
+
+    /// Polls web3signer until it reports every key of `pubkeys` as loaded, and
+    /// returns the ones still missing when `timeout` fires.
+    pub async fn wait_for_keys(
+        &self,
+        pubkeys: &BTreeSet<String>,
+        timeout: Duration,
+    ) -> Result<Vec<String>> {
+        let start = Instant::now();
+        let poll_interval = Duration::from_secs(1);
+        loop {
+            let missing_pubkeys = match self.public_keys().await {
+                Ok(loaded_pubkeys) => pubkeys
+                    .iter()
+                    .filter(|pubkey| !loaded_pubkeys.contains(&normalize_pubkey(pubkey)))
+                    .cloned()
+                    .collect(),
+                Err(error) => {
+                    warn!("Failed to list web3signer public keys: {}", error);
+                    pubkeys.iter().cloned().collect::<Vec<_>>()
+                }
+            };
+            if missing_pubkeys.is_empty() || start.elapsed() + poll_interval > timeout {
+                return Ok(missing_pubkeys);
+            }
+            info!(
+                "Waiting for web3signer to load {} keys",
+                missing_pubkeys.len()
+            );
+            sleep(poll_interval).await;
+        }
+    }
+}
+
+pub fn build_web3signer_client(config: &Config) -> Result<Option<Web3signerClient>> {
+    match &config.web3signer_url {
+        Some(web3signer_url) => {
+            let http_client = tls_client_builder(config)?.build()?;
+            Ok(Some(Web3signerClient::new(http_client, web3signer_url)?))
+        }
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,53 @@
+use super::*;
+use serde_json::json;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const PUBKEY: &str = "0x8000025593183bad1730e78b87b6bce428492e3bf9142d2609032daf674596f955d6403481c7d84809905a262c0136e2";
+const OTHER_PUBKEY: &str = "0x9612d7a727c9d0a22e185a1c768478dfe919cada9266988cb32359c11f2b7b27f4ae4040902382ae2910c15e2b420d07";
+
+#[tokio::test]
+async fn test_reload() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/signer/reload"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let url = format!("{}/signer", mock_server.uri());
+    let web3signer_client = Web3signerClient::new(Client::new(), &url).unwrap();
+    assert!(web3signer_client.reload().await.is_ok());
+
+    let web3signer_client = Web3signerClient::new(Client::new(), &mock_server.uri()).unwrap();
+    assert!(web3signer_client.reload().await.is_err());
+}
+
+#[tokio::test]
+async fn test_wait_for_keys() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/eth2/publicKeys"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!([PUBKEY.to_uppercase().replace("0X", "0x")])),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let web3signer_client = Web3signerClient::new(Client::new(), &mock_server.uri()).unwrap();
+    let pubkeys = BTreeSet::from([PUBKEY.to_string()]);
+    let missing_pubkeys = web3signer_client
+        .wait_for_keys(&pubkeys, Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert!(missing_pubkeys.is_empty());
+
+    let pubkeys = BTreeSet::from([PUBKEY.to_string(), OTHER_PUBKEY.to_string()]);
+    let missing_pubkeys = web3signer_client
+        .wait_for_keys(&pubkeys, Duration::from_secs(2))
+        .await
+        .unwrap();
+    assert_eq!(missing_pubkeys, vec![OTHER_PUBKEY.to_string()]);
+}